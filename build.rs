@@ -2,6 +2,8 @@ use std::fs;
 use std::path::Path;
 
 fn main() {
+    // src/pb/abi.rs is generated, edit abi.proto instead
+    println!("cargo:rerun-if-changed=abi.proto");
     let target_path = Path::new("src/pb");
 
     if !target_path.exists() {
@@ -0,0 +1,275 @@
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::SystemTime,
+};
+
+use bytes::Bytes;
+use lru::LruCache;
+use tracing::{info, warn};
+
+use super::{CacheKey, CacheStats, CacheStore};
+
+const TMP_DIR: &str = "tmp";
+
+/// On-disk tier keyed by `CacheKey`. Entries live at `{root}/{key[..2]}/{key}`, are written
+/// atomically through `{root}/tmp` and evicted in LRU order once `max_bytes` is exceeded.
+pub struct DiskStore {
+    name: String,
+    root: PathBuf,
+    index: Mutex<DiskIndex>,
+    tmp_counter: AtomicU64,
+}
+
+struct DiskIndex {
+    /// Entry sizes in bytes, in recency order.
+    entries: LruCache<CacheKey, u64>,
+    bytes: u64,
+    max_bytes: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl DiskStore {
+    /// Open the cache at `root`, re-indexing existing entries oldest first.
+    pub fn open(
+        name: impl Into<String>,
+        root: impl Into<PathBuf>,
+        max_bytes: u64,
+    ) -> io::Result<Self> {
+        let root = root.into();
+        let name = name.into();
+
+        // Leftovers of interrupted writes
+        let tmp = root.join(TMP_DIR);
+        if tmp.exists() {
+            fs::remove_dir_all(&tmp)?;
+        }
+        fs::create_dir_all(&tmp)?;
+
+        let mut found = vec![];
+        for shard in fs::read_dir(&root)? {
+            let shard = shard?;
+            if shard.file_name() == TMP_DIR || !shard.file_type()?.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(shard.path())? {
+                let entry = entry?;
+                let Some(key) = entry.file_name().to_str().and_then(|s| s.parse().ok()) else {
+                    continue;
+                };
+                let meta = entry.metadata()?;
+                let modified = meta.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                found.push((modified, key, meta.len()));
+            }
+        }
+        found.sort();
+
+        let store = Self {
+            name,
+            root,
+            index: Mutex::new(DiskIndex {
+                entries: LruCache::unbounded(),
+                bytes: 0,
+                max_bytes,
+                hits: 0,
+                misses: 0,
+            }),
+            tmp_counter: AtomicU64::new(0),
+        };
+        {
+            let mut index = store.index.lock().unwrap();
+            for (_, key, size) in found {
+                index.entries.put(key, size);
+                index.bytes += size;
+            }
+            store.evict(&mut index);
+            info!(
+                "Indexed {} {} cache entries, {} bytes",
+                index.entries.len(),
+                &store.name,
+                index.bytes
+            );
+        }
+        Ok(store)
+    }
+
+    fn path(&self, key: &CacheKey) -> PathBuf {
+        let name = key.to_string();
+        self.root.join(&name[..2]).join(name)
+    }
+
+    fn evict(&self, index: &mut DiskIndex) {
+        while index.bytes > index.max_bytes {
+            let Some((key, size)) = index.entries.pop_lru() else {
+                break;
+            };
+            index.bytes -= size;
+            if let Err(e) = fs::remove_file(self.path(&key)) {
+                warn!("Failed to evict cache entry {}: {}", key, e);
+            }
+        }
+    }
+
+    fn write(&self, key: &CacheKey, value: &[u8]) -> io::Result<()> {
+        let tmp = self.root.join(TMP_DIR).join(format!(
+            "{}.{}",
+            key,
+            self.tmp_counter.fetch_add(1, Ordering::Relaxed)
+        ));
+        let path = self.path(key);
+
+        let result = (|| {
+            let mut file = File::create(&tmp)?;
+            file.write_all(value)?;
+            file.sync_data()?;
+            fs::create_dir_all(path.parent().unwrap_or(Path::new(".")))?;
+            fs::rename(&tmp, &path)
+        })();
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        result
+    }
+}
+
+impl CacheStore for DiskStore {
+    fn get(&self, key: &CacheKey) -> Option<Bytes> {
+        {
+            let mut index = self.index.lock().unwrap();
+            if index.entries.get(key).is_none() {
+                index.misses += 1;
+                return None;
+            }
+        }
+
+        let path = self.path(key);
+        match fs::read(&path) {
+            Ok(data) => {
+                // Persist recency for the next startup, best effort
+                let _ = File::options()
+                    .write(true)
+                    .open(&path)
+                    .and_then(|f| f.set_modified(SystemTime::now()));
+                self.index.lock().unwrap().hits += 1;
+                Some(data.into())
+            }
+            Err(e) => {
+                warn!("Dropping unreadable cache entry {}: {}", key, e);
+                let mut index = self.index.lock().unwrap();
+                if let Some(size) = index.entries.pop(key) {
+                    index.bytes -= size;
+                }
+                index.misses += 1;
+                None
+            }
+        }
+    }
+
+    fn put(&self, key: CacheKey, value: Bytes) {
+        let size = value.len() as u64;
+        {
+            let mut index = self.index.lock().unwrap();
+            if size > index.max_bytes {
+                // Not cached, but the stale entry it replaces must not be served either
+                if let Some(old) = index.entries.pop(&key) {
+                    index.bytes -= old;
+                    if let Err(e) = fs::remove_file(self.path(&key)) {
+                        warn!("Failed to remove cache entry {}: {}", key, e);
+                    }
+                }
+                return;
+            }
+        }
+        if let Err(e) = self.write(&key, &value) {
+            warn!("Failed to write cache entry {}: {}", key, e);
+            return;
+        }
+
+        let mut index = self.index.lock().unwrap();
+        if let Some(old) = index.entries.put(key, size) {
+            index.bytes -= old;
+        }
+        index.bytes += size;
+        self.evict(&mut index);
+    }
+
+    fn stats(&self) -> Vec<CacheStats> {
+        let index = self.index.lock().unwrap();
+        vec![CacheStats {
+            tier: self.name.clone(),
+            entries: index.entries.len(),
+            bytes: index.bytes,
+            max_bytes: index.max_bytes,
+            hits: index.hits,
+            misses: index.misses,
+        }]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("thumbor-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        root
+    }
+
+    #[test]
+    fn test_disk_store_persists_and_evicts() {
+        let root = temp_root("disk-store");
+        let (a, b, c) = (
+            CacheKey::source("https://example.com/a.png"),
+            CacheKey::source("https://example.com/b.png"),
+            CacheKey::source("https://example.com/c.png"),
+        );
+
+        {
+            let store = DiskStore::open("disk", &root, 10).unwrap();
+            store.put(a, Bytes::from_static(b"aaaa"));
+            store.put(b, Bytes::from_static(b"bbbb"));
+            assert_eq!(store.get(&a).unwrap().as_ref(), b"aaaa");
+        }
+
+        // Entries survive a restart
+        let store = DiskStore::open("disk", &root, 10).unwrap();
+        assert_eq!(store.stats()[0].entries, 2);
+        assert_eq!(store.get(&b).unwrap().as_ref(), b"bbbb");
+
+        // a is now the least recently used entry
+        store.put(c, Bytes::from_static(b"cccc"));
+        assert!(store.get(&a).is_none());
+        assert!(!store.path(&a).exists());
+        assert_eq!(store.get(&c).unwrap().as_ref(), b"cccc");
+        assert_eq!(store.stats()[0].bytes, 8);
+
+        // An oversized replacement drops the old entry
+        store.put(c, Bytes::from_static(b"cccccccccccc"));
+        assert!(store.get(&c).is_none());
+        assert!(!store.path(&c).exists());
+        assert_eq!(store.stats()[0].bytes, 4);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_disk_store_drops_missing_files() {
+        let root = temp_root("disk-missing");
+        let store = DiskStore::open("disk", &root, 1024).unwrap();
+        let key = CacheKey::source("https://example.com/a.png");
+        store.put(key, Bytes::from_static(b"data"));
+
+        fs::remove_file(store.path(&key)).unwrap();
+        assert!(store.get(&key).is_none());
+        assert_eq!(store.stats()[0].entries, 0);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,144 @@
+use std::{hash::Hash, sync::Mutex};
+
+use bytes::Bytes;
+use lru::LruCache;
+
+use super::{CacheKey, CacheStats, CacheStore};
+
+/// LRU cache bounded by the total byte size of its values rather than the entry count.
+pub struct ByteLru<K: Hash + Eq> {
+    entries: LruCache<K, Bytes>,
+    bytes: u64,
+    max_bytes: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl<K: Hash + Eq> ByteLru<K> {
+    pub fn new(max_bytes: u64) -> Self {
+        Self {
+            entries: LruCache::unbounded(),
+            bytes: 0,
+            max_bytes,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn get(&mut self, key: &K) -> Option<Bytes> {
+        match self.entries.get(key) {
+            Some(value) => {
+                self.hits += 1;
+                Some(value.clone())
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Insert `value`, evicting least recently used entries to stay within budget.
+    /// Values larger than the whole budget are not cached and drop the entry they replace.
+    pub fn put(&mut self, key: K, value: Bytes) {
+        let size = value.len() as u64;
+        if size > self.max_bytes {
+            if let Some(old) = self.entries.pop(&key) {
+                self.bytes -= old.len() as u64;
+            }
+            return;
+        }
+
+        if let Some(old) = self.entries.put(key, value) {
+            self.bytes -= old.len() as u64;
+        }
+        self.bytes += size;
+
+        while self.bytes > self.max_bytes {
+            match self.entries.pop_lru() {
+                Some((_, evicted)) => self.bytes -= evicted.len() as u64,
+                None => break,
+            }
+        }
+    }
+
+    pub fn stats(&self, tier: &str) -> CacheStats {
+        CacheStats {
+            tier: tier.to_owned(),
+            entries: self.entries.len(),
+            bytes: self.bytes,
+            max_bytes: self.max_bytes,
+            hits: self.hits,
+            misses: self.misses,
+        }
+    }
+}
+
+/// In-memory tier backed by a `ByteLru`.
+pub struct MemoryStore {
+    name: String,
+    lru: Mutex<ByteLru<CacheKey>>,
+}
+
+impl MemoryStore {
+    pub fn new(name: impl Into<String>, max_bytes: u64) -> Self {
+        Self {
+            name: name.into(),
+            lru: Mutex::new(ByteLru::new(max_bytes)),
+        }
+    }
+}
+
+impl CacheStore for MemoryStore {
+    fn get(&self, key: &CacheKey) -> Option<Bytes> {
+        self.lru.lock().unwrap().get(key)
+    }
+
+    fn put(&self, key: CacheKey, value: Bytes) {
+        self.lru.lock().unwrap().put(key, value);
+    }
+
+    fn stats(&self) -> Vec<CacheStats> {
+        vec![self.lru.lock().unwrap().stats(&self.name)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_byte_lru_evicts_by_size() {
+        let mut cache = ByteLru::new(10);
+        cache.put(1, Bytes::from_static(b"aaaa"));
+        cache.put(2, Bytes::from_static(b"bbbb"));
+        assert!(cache.get(&1).is_some());
+
+        // 2 is the least recently used entry now
+        cache.put(3, Bytes::from_static(b"cccc"));
+        assert!(cache.get(&2).is_none());
+        assert!(cache.get(&1).is_some());
+        assert!(cache.get(&3).is_some());
+
+        // too large for the whole budget
+        cache.put(4, Bytes::from_static(b"dddddddddddd"));
+        assert!(cache.get(&4).is_none());
+
+        let stats = cache.stats("memory");
+        assert_eq!((stats.entries, stats.bytes), (2, 8));
+        assert_eq!((stats.hits, stats.misses), (3, 2));
+    }
+
+    #[test]
+    fn test_byte_lru_replaces_existing_entry() {
+        let mut cache = ByteLru::new(10);
+        cache.put("a", Bytes::from_static(b"aaaaaaaa"));
+        cache.put("a", Bytes::from_static(b"aa"));
+        assert_eq!(cache.stats("memory").bytes, 2);
+
+        // an oversized value must not leave the old one behind
+        cache.put("a", Bytes::from_static(b"aaaaaaaaaaaa"));
+        assert!(cache.get(&"a").is_none());
+        assert_eq!(cache.stats("memory").bytes, 0);
+    }
+}
@@ -0,0 +1,275 @@
+use std::{fmt, io, path::Path, str::FromStr, sync::Arc};
+
+use anyhow::bail;
+use bytes::Bytes;
+use image::ImageFormat;
+use reqwest::Url;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+pub(crate) mod disk;
+pub(crate) mod memory;
+
+/// SHA-256 cache key over a canonicalized url, stable across restarts and Rust versions
+/// so it can also name persistent entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct CacheKey([u8; 32]);
+
+impl CacheKey {
+    /// Key of a downloaded source image.
+    pub fn source(url: &str) -> Self {
+        Self::digest(&[b"source", canonical_url(url).as_bytes()])
+    }
+
+    /// Key of a processed result: source url, canonical spec bytes and the output formats
+    /// the request could be served with.
+    pub fn result(url: &str, spec: &[u8], formats: &[ImageFormat]) -> Self {
+        Self::digest(&[
+            b"result",
+            canonical_url(url).as_bytes(),
+            spec,
+            format_list(formats).as_bytes(),
+        ])
+    }
+
+    /// Content hash of a result, served as its ETag: like `result` but over the source
+    /// bytes, so it changes exactly when the output can.
+    pub fn etag(source_digest: &[u8], spec: &[u8], formats: &[ImageFormat]) -> Self {
+        Self::digest(&[
+            b"etag",
+            source_digest,
+            spec,
+            format_list(formats).as_bytes(),
+        ])
+    }
+
+    /// Length-prefix every part so different splits of the same bytes never collide.
+    fn digest(parts: &[&[u8]]) -> Self {
+        let mut hasher = Sha256::new();
+        for part in parts {
+            hasher.update((part.len() as u64).to_be_bytes());
+            hasher.update(part);
+        }
+        Self(hasher.finalize().into())
+    }
+}
+
+fn format_list(formats: &[ImageFormat]) -> String {
+    formats
+        .iter()
+        .map(|f| f.to_mime_type())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+impl fmt::Display for CacheKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|b| write!(f, "{:02x}", b))
+    }
+}
+
+/// Canonical form of `url` for cache keys: lowercase scheme and host, no default port,
+/// no fragment and query parameters sorted by name. Unparsable urls are used verbatim.
+pub fn canonical_url(url: &str) -> String {
+    let Ok(mut url) = Url::parse(url) else {
+        return url.to_owned();
+    };
+    url.set_fragment(None);
+
+    // Sorted as raw segments, decoding would merge `+` with `%20` and `flag` with `flag=`
+    let mut segments: Vec<&str> = url
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .filter(|s| !s.is_empty())
+        .collect();
+    // Stable, so repeated parameters keep their order, which the origin may depend on
+    segments.sort_by_key(|s| s.split_once('=').map_or(*s, |(name, _)| name));
+    let query = segments.join("&");
+    url.set_query((!query.is_empty()).then_some(query.as_str()));
+    url.into()
+}
+
+impl FromStr for CacheKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != 64 || !s.is_ascii() {
+            bail!("invalid cache key {}", s);
+        }
+        let mut key = [0_u8; 32];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16)?;
+        }
+        Ok(Self(key))
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CacheStats {
+    pub tier: String,
+    pub entries: usize,
+    pub bytes: u64,
+    pub max_bytes: u64,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// A cache tier mapping `CacheKey`s to bytes. Implementations may block on IO.
+pub trait CacheStore: Send + Sync {
+    fn get(&self, key: &CacheKey) -> Option<Bytes>;
+    fn put(&self, key: CacheKey, value: Bytes);
+    fn stats(&self) -> Vec<CacheStats>;
+}
+
+/// Chains tiers from fastest to slowest. Lookups fall through to slower tiers and promote
+/// hits into the faster ones, writes go to every tier.
+pub struct Layered {
+    tiers: Vec<Box<dyn CacheStore>>,
+}
+
+impl Layered {
+    pub fn new(tiers: Vec<Box<dyn CacheStore>>) -> Self {
+        Self { tiers }
+    }
+
+    /// A memory tier named `{name}_memory`, backed by a disk tier at `{dir}/{name}` when
+    /// a cache directory is configured.
+    pub fn open(
+        name: &str,
+        memory_bytes: u64,
+        dir: Option<&Path>,
+        disk_bytes: u64,
+    ) -> io::Result<Self> {
+        let mut tiers: Vec<Box<dyn CacheStore>> = vec![Box::new(memory::MemoryStore::new(
+            format!("{}_memory", name),
+            memory_bytes,
+        ))];
+        if let Some(dir) = dir {
+            tiers.push(Box::new(disk::DiskStore::open(
+                format!("{}_disk", name),
+                dir.join(name),
+                disk_bytes,
+            )?));
+        }
+        Ok(Self::new(tiers))
+    }
+}
+
+/// Look `key` up on the blocking pool, since tiers may read from disk.
+pub async fn get_blocking(store: Arc<dyn CacheStore>, key: CacheKey) -> Option<Bytes> {
+    tokio::task::spawn_blocking(move || store.get(&key))
+        .await
+        .ok()
+        .flatten()
+}
+
+/// Store `value` in the background without delaying the caller.
+pub fn put_background(store: Arc<dyn CacheStore>, key: CacheKey, value: Bytes) {
+    tokio::task::spawn_blocking(move || store.put(key, value));
+}
+
+impl CacheStore for Layered {
+    fn get(&self, key: &CacheKey) -> Option<Bytes> {
+        let (i, value) = self
+            .tiers
+            .iter()
+            .enumerate()
+            .find_map(|(i, tier)| tier.get(key).map(|value| (i, value)))?;
+        for tier in &self.tiers[..i] {
+            tier.put(*key, value.clone());
+        }
+        Some(value)
+    }
+
+    fn put(&self, key: CacheKey, value: Bytes) {
+        for tier in &self.tiers {
+            tier.put(key, value.clone());
+        }
+    }
+
+    fn stats(&self) -> Vec<CacheStats> {
+        self.tiers.iter().flat_map(|tier| tier.stats()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_canonical_url() {
+        assert_eq!(
+            canonical_url("HTTP://Example.COM:80/a.png?b=2&a=1&a=0#top"),
+            "http://example.com/a.png?a=1&a=0&b=2"
+        );
+        assert_ne!(
+            canonical_url("https://example.com/a.png?a=1&a=2"),
+            canonical_url("https://example.com/a.png?a=2&a=1")
+        );
+        assert_ne!(
+            canonical_url("https://example.com/a.png?q=a%20b"),
+            canonical_url("https://example.com/a.png?q=a+b")
+        );
+        assert_ne!(
+            canonical_url("https://example.com/a.png?flag"),
+            canonical_url("https://example.com/a.png?flag=")
+        );
+        assert_eq!(
+            canonical_url("https://example.com:443/a%20b.png?"),
+            "https://example.com/a%20b.png"
+        );
+        assert_eq!(
+            canonical_url("https://example.com:8443/a.png"),
+            "https://example.com:8443/a.png"
+        );
+    }
+
+    #[test]
+    fn test_cache_keys_are_stable_and_distinct() {
+        let key = CacheKey::source("https://EXAMPLE.com/a.png?y=1&x=2");
+        assert_eq!(key, CacheKey::source("https://example.com/a.png?x=2&y=1"));
+        assert_ne!(key, CacheKey::source("https://example.com/b.png?x=2&y=1"));
+        // must never change, persistent entries are named after it
+        assert_eq!(
+            key.to_string(),
+            "57eecae66d0a8cc75cae3225caf563abc80c5d8a70797d74cac7ab28d2018788"
+        );
+
+        let url = "https://example.com/a.png";
+        let png = CacheKey::result(url, b"spec", &[ImageFormat::Png]);
+        assert_ne!(png, CacheKey::result(url, b"spec", &[ImageFormat::Jpeg]));
+        assert_ne!(png, CacheKey::result(url, b"spec2", &[ImageFormat::Png]));
+        assert_ne!(png, CacheKey::source(url));
+
+        let digest = [7_u8; 32];
+        let etag = CacheKey::etag(&digest, b"spec", &[ImageFormat::Png]);
+        assert_ne!(etag, CacheKey::etag(&[8; 32], b"spec", &[ImageFormat::Png]));
+        assert_ne!(etag, CacheKey::etag(&digest, b"spec", &[ImageFormat::Jpeg]));
+    }
+
+    #[test]
+    fn test_cache_key_round_trips_through_hex() {
+        let key = CacheKey::source("https://example.com/a.png");
+        assert_eq!(key.to_string().parse::<CacheKey>().unwrap(), key);
+        assert!("not-a-key".parse::<CacheKey>().is_err());
+    }
+
+    #[test]
+    fn test_layered_promotes_hits_from_slower_tiers() {
+        let layered = Layered::new(vec![
+            Box::new(memory::MemoryStore::new("front", 1024)),
+            Box::new(memory::MemoryStore::new("back", 1024)),
+        ]);
+        let key = CacheKey::source("https://example.com/a.png");
+        layered.tiers[1].put(key, Bytes::from_static(b"data"));
+
+        assert_eq!(layered.get(&key).unwrap().as_ref(), b"data");
+        let stats = layered.stats();
+        assert_eq!((stats[0].entries, stats[0].misses), (1, 1));
+        assert_eq!(stats[1].hits, 1);
+
+        assert!(layered.get(&key).is_some());
+        assert_eq!(layered.stats()[0].hits, 1);
+    }
+}
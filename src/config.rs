@@ -0,0 +1,189 @@
+use std::{env, net::SocketAddr, path::PathBuf, str::FromStr, time::Duration};
+
+use tracing::warn;
+
+use crate::{
+    engine::ImageLimits,
+    pool::PoolConfig,
+    source::{DownloadLimits, SourcePolicy},
+};
+
+/// Server configuration, read from `THUMBOR_*` environment variables.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub addr: SocketAddr,
+    /// Wall-clock budget of an image request, including download and processing.
+    pub request_timeout: Duration,
+    pub security: SecurityConfig,
+    pub source: SourcePolicy,
+    pub download: DownloadLimits,
+    pub image: ImageLimits,
+    pub cache: CacheConfig,
+    pub pool: PoolConfig,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SecurityConfig {
+    /// Secret used to verify `/image/{signature}/{spec}/{url}` requests.
+    pub secret: Option<String>,
+    /// Serve `/image/unsafe/{spec}/{url}` without a signature, for development only.
+    pub allow_unsafe: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct CacheConfig {
+    /// Byte budgets of the in-memory source and result tiers.
+    pub source_max_bytes: u64,
+    pub result_max_bytes: u64,
+    /// Directory of the persistent tiers, disabled when unset.
+    pub dir: Option<PathBuf>,
+    pub disk_source_max_bytes: u64,
+    pub disk_result_max_bytes: u64,
+    /// Lifetime of cached sources whose upstream sent no max-age.
+    pub source_ttl: Duration,
+    /// How long expired sources are still served while they are revalidated in the background.
+    pub stale_while_revalidate: Duration,
+    /// `Cache-Control` max-age of served images.
+    pub max_age: Duration,
+    /// Mark served images `immutable`, for sources whose urls never change content.
+    pub immutable: bool,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            source_max_bytes: 128 * 1024 * 1024,
+            result_max_bytes: 256 * 1024 * 1024,
+            dir: None,
+            disk_source_max_bytes: 2 * 1024 * 1024 * 1024,
+            disk_result_max_bytes: 2 * 1024 * 1024 * 1024,
+            source_ttl: Duration::from_secs(60 * 60),
+            stale_while_revalidate: Duration::from_secs(24 * 60 * 60),
+            max_age: Duration::from_secs(24 * 60 * 60),
+            immutable: false,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            addr: SocketAddr::from(([127, 0, 0, 1], 3000)),
+            request_timeout: Duration::from_secs(60),
+            security: SecurityConfig::default(),
+            source: SourcePolicy::default(),
+            download: DownloadLimits::default(),
+            image: ImageLimits::default(),
+            cache: CacheConfig::default(),
+            pool: PoolConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        let config = Self {
+            addr: env_or("THUMBOR_ADDR", default.addr),
+            request_timeout: env_secs("THUMBOR_REQUEST_TIMEOUT_SECS", default.request_timeout),
+            security: SecurityConfig {
+                secret: env::var("THUMBOR_SECRET").ok().filter(|s| !s.is_empty()),
+                allow_unsafe: env_or("THUMBOR_ALLOW_UNSAFE", default.security.allow_unsafe),
+            },
+            source: SourcePolicy {
+                allowed_schemes: env_list(
+                    "THUMBOR_ALLOWED_SCHEMES",
+                    default.source.allowed_schemes,
+                ),
+                allowed_hosts: env_list("THUMBOR_ALLOWED_HOSTS", default.source.allowed_hosts),
+                denied_hosts: env_list("THUMBOR_DENIED_HOSTS", default.source.denied_hosts),
+                allow_private: env_or(
+                    "THUMBOR_ALLOW_PRIVATE_NETWORKS",
+                    default.source.allow_private,
+                ),
+            },
+            download: DownloadLimits {
+                max_bytes: env_or("THUMBOR_MAX_SOURCE_BYTES", default.download.max_bytes),
+                connect_timeout: env_secs(
+                    "THUMBOR_CONNECT_TIMEOUT_SECS",
+                    default.download.connect_timeout,
+                ),
+                timeout: env_secs("THUMBOR_FETCH_TIMEOUT_SECS", default.download.timeout),
+                max_redirects: env_or("THUMBOR_MAX_REDIRECTS", default.download.max_redirects),
+            },
+            image: ImageLimits {
+                max_width: env_or("THUMBOR_MAX_WIDTH", default.image.max_width),
+                max_height: env_or("THUMBOR_MAX_HEIGHT", default.image.max_height),
+                max_pixels: env_or("THUMBOR_MAX_PIXELS", default.image.max_pixels),
+                max_alloc: env_or("THUMBOR_MAX_DECODE_ALLOC", default.image.max_alloc),
+            },
+            cache: CacheConfig {
+                source_max_bytes: env_or(
+                    "THUMBOR_SOURCE_CACHE_BYTES",
+                    default.cache.source_max_bytes,
+                ),
+                result_max_bytes: env_or(
+                    "THUMBOR_RESULT_CACHE_BYTES",
+                    default.cache.result_max_bytes,
+                ),
+                dir: env::var_os("THUMBOR_CACHE_DIR").map(PathBuf::from),
+                disk_source_max_bytes: env_or(
+                    "THUMBOR_DISK_SOURCE_CACHE_BYTES",
+                    default.cache.disk_source_max_bytes,
+                ),
+                disk_result_max_bytes: env_or(
+                    "THUMBOR_DISK_RESULT_CACHE_BYTES",
+                    default.cache.disk_result_max_bytes,
+                ),
+                source_ttl: env_secs("THUMBOR_SOURCE_TTL_SECS", default.cache.source_ttl),
+                stale_while_revalidate: env_secs(
+                    "THUMBOR_STALE_WHILE_REVALIDATE_SECS",
+                    default.cache.stale_while_revalidate,
+                ),
+                max_age: env_secs("THUMBOR_MAX_AGE_SECS", default.cache.max_age),
+                immutable: env_or("THUMBOR_CACHE_IMMUTABLE", default.cache.immutable),
+            },
+            pool: PoolConfig {
+                workers: env_or("THUMBOR_WORKERS", default.pool.workers),
+                max_queue: env_or("THUMBOR_WORKER_QUEUE", default.pool.max_queue),
+            },
+        };
+
+        if config.security.secret.is_none() {
+            warn!("THUMBOR_SECRET is not set, signed requests will be rejected");
+        }
+        if config.security.allow_unsafe {
+            warn!("THUMBOR_ALLOW_UNSAFE is enabled, do not use it in production");
+        }
+        config
+    }
+}
+
+/// Parse an environment variable, falling back to `default` when unset or invalid.
+fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    match env::var(key) {
+        Ok(value) => value.parse().unwrap_or_else(|_| {
+            warn!("Invalid value {:?} for {}, using default", value, key);
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+/// Parse a duration given in (fractional) seconds.
+fn env_secs(key: &str, default: Duration) -> Duration {
+    Duration::try_from_secs_f64(env_or(key, default.as_secs_f64())).unwrap_or(default)
+}
+
+/// Parse a comma separated environment variable, falling back to `default` when unset.
+fn env_list(key: &str, default: Vec<String>) -> Vec<String> {
+    match env::var(key) {
+        Ok(value) => value
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(String::from)
+            .collect(),
+        Err(_) => default,
+    }
+}
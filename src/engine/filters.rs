@@ -0,0 +1,342 @@
+use image::DynamicImage;
+
+use crate::pb::abi::ColorAdjust;
+
+/// Rec. 709 luma weights.
+const LUMA: [f32; 3] = [0.213, 0.715, 0.072];
+/// Channel gain per unit of temperature and tint.
+const WHITE_BALANCE_GAIN: f32 = 0.2;
+
+type Matrix = [[f32; 3]; 3];
+
+/// Gaussian blurs wider than this use the box approximation, whose cost does not grow
+/// with sigma.
+const FAST_BLUR_SIGMA: f32 = 5.0;
+
+/// Gaussian blur of `image` in its own pixel type.
+pub fn blur(image: &DynamicImage, sigma: f32) -> DynamicImage {
+    if sigma > FAST_BLUR_SIGMA {
+        image.fast_blur(sigma)
+    } else {
+        image.blur(sigma)
+    }
+}
+
+/// Add `amount` times the difference between `image` and its blur by `sigma`, leaving
+/// alone differences of `threshold` levels out of 255 or less. Alpha is kept as is.
+pub fn unsharp_mask(image: &mut DynamicImage, sigma: f32, amount: f32, threshold: f32) {
+    let mut blurred = blur(image, sigma);
+    let layout = Layout::of(image);
+    let threshold = threshold / 255.0;
+    let sharpen = |v: f32, blurred: f32| {
+        let diff = v - blurred;
+        if diff.abs() > threshold {
+            v + amount * diff
+        } else {
+            v
+        }
+    };
+
+    match (samples(image), samples(&mut blurred)) {
+        (Samples::U8(s), Samples::U8(b)) => zip_colors(s, b, layout, sharpen),
+        (Samples::U16(s), Samples::U16(b)) => zip_colors(s, b, layout, sharpen),
+        (Samples::F32(s), Samples::F32(b)) => zip_colors(s, b, layout, sharpen),
+        _ => unreachable!("blurring keeps the pixel type"),
+    }
+}
+
+/// Apply `adjust` to every pixel, see `ColorAdjust` in abi.proto. Alpha and the pixel type
+/// are kept, so gray images stay gray.
+pub fn adjust_colors(image: &mut DynamicImage, adjust: &ColorAdjust) {
+    let exposure = adjust.exposure.exp2();
+    let white_balance = [
+        1.0 + WHITE_BALANCE_GAIN * adjust.temperature,
+        1.0 - WHITE_BALANCE_GAIN * adjust.tint,
+        1.0 - WHITE_BALANCE_GAIN * adjust.temperature,
+    ];
+    let gamma = if adjust.gamma == 0.0 {
+        1.0
+    } else {
+        1.0 / adjust.gamma
+    };
+    let color = multiply(
+        hue_rotation(adjust.hue),
+        saturation(1.0 + adjust.saturation),
+    );
+
+    map_rgb(image, |rgb| {
+        let rgb = [0, 1, 2].map(|c| {
+            let v = rgb[c] * exposure * white_balance[c] + adjust.brightness;
+            v.clamp(0.0, 1.0).powf(gamma)
+        });
+        transform(&color, rgb)
+    })
+}
+
+/// Luma of a normalized RGB color.
+pub fn luma(rgb: [f32; 3]) -> f32 {
+    LUMA[0] * rgb[0] + LUMA[1] * rgb[1] + LUMA[2] * rgb[2]
+}
+
+/// Map the color of every pixel as normalized RGB, in place and in the pixel type of
+/// `image`. Alpha is kept, gray pixels are mapped as gray RGB and stored as the luma of the
+/// result.
+pub fn map_rgb(image: &mut DynamicImage, f: impl Fn([f32; 3]) -> [f32; 3]) {
+    let layout = Layout::of(image);
+    match samples(image) {
+        Samples::U8(s) => map_colors(s, layout, &f),
+        Samples::U16(s) => map_colors(s, layout, &f),
+        Samples::F32(s) => map_colors(s, layout, &f),
+    }
+}
+
+/// Mix every color with its luma, `0` for grayscale and `1` for the identity.
+fn saturation(s: f32) -> Matrix {
+    [0, 1, 2].map(|row| {
+        [0, 1, 2].map(|col| {
+            let identity = if row == col { 1.0 } else { 0.0 };
+            LUMA[col] + s * (identity - LUMA[col])
+        })
+    })
+}
+
+/// Rotation around the gray axis, keeping luma, as in the CSS `hue-rotate` filter.
+fn hue_rotation(degrees: f32) -> Matrix {
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    let [r, g, b] = LUMA;
+    [
+        [
+            r + cos * (1.0 - r) - sin * r,
+            g - cos * g - sin * g,
+            b - cos * b + sin * (1.0 - b),
+        ],
+        [
+            r - cos * r + sin * 0.143,
+            g + cos * (1.0 - g) + sin * 0.140,
+            b - cos * b - sin * 0.283,
+        ],
+        [
+            r - cos * r - sin * (1.0 - r),
+            g - cos * g + sin * g,
+            b + cos * (1.0 - b) + sin * b,
+        ],
+    ]
+}
+
+fn multiply(a: Matrix, b: Matrix) -> Matrix {
+    [0, 1, 2].map(|row| [0, 1, 2].map(|col| (0..3).map(|k| a[row][k] * b[k][col]).sum()))
+}
+
+fn transform(m: &Matrix, rgb: [f32; 3]) -> [f32; 3] {
+    m.map(|row| row[0] * rgb[0] + row[1] * rgb[1] + row[2] * rgb[2])
+}
+
+/// Channels per pixel, of which the first `colors` are color and any other is alpha.
+#[derive(Clone, Copy)]
+struct Layout {
+    channels: usize,
+    colors: usize,
+}
+
+impl Layout {
+    fn of(image: &DynamicImage) -> Self {
+        let color = image.color();
+        Self {
+            channels: color.channel_count() as usize,
+            colors: if color.has_color() { 3 } else { 1 },
+        }
+    }
+}
+
+/// The samples of an image in their own channel type.
+enum Samples<'a> {
+    U8(&'a mut [u8]),
+    U16(&'a mut [u16]),
+    F32(&'a mut [f32]),
+}
+
+fn samples(image: &mut DynamicImage) -> Samples<'_> {
+    match image {
+        DynamicImage::ImageLuma8(i) => Samples::U8(i),
+        DynamicImage::ImageLumaA8(i) => Samples::U8(i),
+        DynamicImage::ImageRgb8(i) => Samples::U8(i),
+        DynamicImage::ImageRgba8(i) => Samples::U8(i),
+        DynamicImage::ImageLuma16(i) => Samples::U16(i),
+        DynamicImage::ImageLumaA16(i) => Samples::U16(i),
+        DynamicImage::ImageRgb16(i) => Samples::U16(i),
+        DynamicImage::ImageRgba16(i) => Samples::U16(i),
+        DynamicImage::ImageRgb32F(i) => Samples::F32(i),
+        DynamicImage::ImageRgba32F(i) => Samples::F32(i),
+        _ => unreachable!("unknown pixel type {:?}", image.color()),
+    }
+}
+
+/// A channel type, read and written as a value in 0-1.
+trait Channel: Copy {
+    fn to_unit(self) -> f32;
+    fn from_unit(v: f32) -> Self;
+}
+
+impl Channel for u8 {
+    fn to_unit(self) -> f32 {
+        self as f32 / u8::MAX as f32
+    }
+
+    fn from_unit(v: f32) -> Self {
+        (v.clamp(0.0, 1.0) * u8::MAX as f32).round() as u8
+    }
+}
+
+impl Channel for u16 {
+    fn to_unit(self) -> f32 {
+        self as f32 / u16::MAX as f32
+    }
+
+    fn from_unit(v: f32) -> Self {
+        (v.clamp(0.0, 1.0) * u16::MAX as f32).round() as u16
+    }
+}
+
+impl Channel for f32 {
+    fn to_unit(self) -> f32 {
+        self
+    }
+
+    fn from_unit(v: f32) -> Self {
+        v.clamp(0.0, 1.0)
+    }
+}
+
+fn map_colors<S: Channel>(samples: &mut [S], layout: Layout, f: &impl Fn([f32; 3]) -> [f32; 3]) {
+    for pixel in samples.chunks_exact_mut(layout.channels) {
+        if layout.colors == 1 {
+            let l = pixel[0].to_unit();
+            pixel[0] = S::from_unit(luma(f([l; 3])));
+        } else {
+            let rgb = f([0, 1, 2].map(|c| pixel[c].to_unit()));
+            for c in 0..3 {
+                pixel[c] = S::from_unit(rgb[c]);
+            }
+        }
+    }
+}
+
+/// Replace every color sample with `f` of it and the same sample of `other`.
+fn zip_colors<S: Channel>(
+    samples: &mut [S],
+    other: &[S],
+    layout: Layout,
+    f: impl Fn(f32, f32) -> f32,
+) {
+    let pixels = samples.chunks_exact_mut(layout.channels);
+    for (pixel, other) in pixels.zip(other.chunks_exact(layout.channels)) {
+        for c in 0..layout.colors {
+            pixel[c] = S::from_unit(f(pixel[c].to_unit(), other[c].to_unit()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ColorType, GenericImageView, Luma, LumaA};
+
+    #[test]
+    fn test_unsharp_mask_keeps_pixel_type_and_alpha() {
+        // a vertical edge with an alpha edge across it
+        let image = image::ImageBuffer::from_fn(20, 20, |x, y| {
+            LumaA([
+                if x < 10 { 100 } else { 150 },
+                if y < 10 { 255 } else { 64 },
+            ])
+        });
+        let image = DynamicImage::ImageLumaA8(image);
+
+        let mut sharpened = image.clone();
+        unsharp_mask(&mut sharpened, 1.0, 2.0, 0.0);
+        assert_eq!(sharpened.color(), ColorType::La8);
+        for (x, y, pixel) in sharpened.pixels() {
+            assert_eq!(pixel[3], image.get_pixel(x, y)[3]);
+        }
+        // contrast grows across the edge, flat areas stay flat
+        assert!(sharpened.get_pixel(9, 0)[0] < 100);
+        assert!(sharpened.get_pixel(10, 0)[0] > 150);
+        assert_eq!(sharpened.get_pixel(0, 0)[0], 100);
+
+        let flat = DynamicImage::ImageLuma16(image::ImageBuffer::from_pixel(8, 8, Luma([1000])));
+        let mut sharpened = flat.clone();
+        unsharp_mask(&mut sharpened, 1.0, 2.0, 0.0);
+        assert_eq!(sharpened, flat);
+    }
+
+    #[test]
+    fn test_map_rgb_works_in_native_pixel_type() {
+        let rgba8 = DynamicImage::ImageRgba8(image::ImageBuffer::from_fn(4, 4, |x, y| {
+            image::Rgba([(x * 60) as u8, (y * 60) as u8, 200, 77])
+        }));
+        let mut inverted = rgba8.clone();
+        map_rgb(&mut inverted, |rgb| rgb.map(|v| 1.0 - v));
+        assert_eq!(inverted.color(), ColorType::Rgba8);
+        for (x, y, pixel) in inverted.pixels() {
+            let source = rgba8.get_pixel(x, y);
+            assert_eq!(pixel.0, [255 - source[0], 255 - source[1], 55, 77]);
+        }
+
+        // gray stays gray, as the luma of the mapped color
+        let mut luma = DynamicImage::ImageLuma16(image::ImageBuffer::from_pixel(2, 2, Luma([0])));
+        map_rgb(&mut luma, |_| [1.0, 0.0, 0.0]);
+        assert_eq!(luma.color(), ColorType::L16);
+        assert_eq!(luma.as_luma16().unwrap().get_pixel(0, 0)[0], 13959);
+    }
+
+    #[test]
+    fn test_adjust_colors_keeps_pixel_type_and_alpha() {
+        let rgba16 = DynamicImage::ImageRgba16(image::ImageBuffer::from_pixel(
+            2,
+            2,
+            image::Rgba([30000u16, 20000, 10000, 12345]),
+        ));
+        let adjust = |image: &DynamicImage, adjust: ColorAdjust| {
+            let mut image = image.clone();
+            adjust_colors(&mut image, &adjust);
+            image
+        };
+        let gray = |image: &DynamicImage| {
+            let [r, g, b, a] = image.to_rgba16().get_pixel(0, 0).0;
+            assert_eq!(a, 12345);
+            (r, g, b)
+        };
+
+        let desaturated = adjust(
+            &rgba16,
+            ColorAdjust {
+                saturation: -1.0,
+                ..Default::default()
+            },
+        );
+        assert_eq!(desaturated.color(), ColorType::Rgba16);
+        let (r, g, b) = gray(&desaturated);
+        assert!(r == g && g == b);
+
+        let exposed = adjust(
+            &rgba16,
+            ColorAdjust {
+                exposure: 1.0,
+                ..Default::default()
+            },
+        );
+        assert_eq!(gray(&exposed), (60000, 40000, 20000));
+
+        // hue rotation and saturation keep gray pixels gray
+        let luma = DynamicImage::ImageLuma8(image::ImageBuffer::from_pixel(2, 2, Luma([90])));
+        let adjusted = adjust(
+            &luma,
+            ColorAdjust {
+                hue: 120.0,
+                saturation: 0.5,
+                ..Default::default()
+            },
+        );
+        assert_eq!(adjusted, luma);
+    }
+}
@@ -0,0 +1,375 @@
+use std::fmt;
+
+use super::EngineError;
+use crate::pb::abi::{Crop, FocalPoint, Gravity, resize::Fit};
+
+/// A window in image coordinates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl fmt::Display for Rect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{},{},{},{}", self.x, self.y, self.width, self.height)
+    }
+}
+
+/// Fill in a zero `width` or `height` from the aspect ratio of `src`. Zero for both keeps
+/// the source size.
+pub fn auto_size((src_w, src_h): (u32, u32), width: u32, height: u32) -> (u32, u32) {
+    let scale = |len: u32, num: u32, den: u32| {
+        ((len as u64 * num as u64 + den as u64 / 2) / den.max(1) as u64).max(1) as u32
+    };
+    match (width, height) {
+        (0, 0) => (src_w, src_h),
+        (0, h) => (scale(h, src_w, src_h), h),
+        (w, 0) => (w, scale(w, src_h, src_w)),
+        (w, h) => (w, h),
+    }
+}
+
+/// Size `src` is scaled to when fitting it into `width` x `height`, before the letterbox
+/// of `Contain` or the crop of `Cover`.
+pub fn scaled_size(src: (u32, u32), (width, height): (u32, u32), fit: Fit) -> (u32, u32) {
+    let (src_w, src_h) = (src.0 as f64, src.1 as f64);
+    let (rw, rh) = (width as f64 / src_w, height as f64 / src_h);
+    let ratio = match fit {
+        Fit::Fill => return (width, height),
+        Fit::Contain => rw.min(rh),
+        Fit::Cover => rw.max(rh),
+        Fit::Inside => rw.min(rh).min(1.0),
+        Fit::Outside => rw.max(rh).min(1.0),
+    };
+    let scale = |len: f64| ((len * ratio).round() as u32).max(1);
+    match fit {
+        // Keep the box side exact so rounding never leaves a one pixel gap
+        Fit::Contain if rw <= rh => (width, scale(src_h).min(height)),
+        Fit::Contain => (scale(src_w).min(width), height),
+        Fit::Cover if rw >= rh => (width, scale(src_h).max(height)),
+        Fit::Cover => (scale(src_w).max(width), height),
+        _ => (scale(src_w), scale(src_h)),
+    }
+}
+
+/// Largest `aspect_w:aspect_h` window that fits in `src`.
+pub fn aspect_window((src_w, src_h): (u32, u32), (aspect_w, aspect_h): (u32, u32)) -> (u32, u32) {
+    let (aspect_w, aspect_h) = (aspect_w.max(1) as u64, aspect_h.max(1) as u64);
+    if src_w as u64 * aspect_h <= src_h as u64 * aspect_w {
+        let height = (src_w as u64 * aspect_h + aspect_w / 2) / aspect_w;
+        (src_w, (height as u32).clamp(1, src_h))
+    } else {
+        let width = (src_h as u64 * aspect_w + aspect_h / 2) / aspect_h;
+        ((width as u32).clamp(1, src_w), src_h)
+    }
+}
+
+/// Size of the bounding box of `src` rotated by `degrees`.
+pub fn rotated_size((src_w, src_h): (u32, u32), degrees: f32) -> (u32, u32) {
+    let (sin, cos) = (degrees as f64).to_radians().sin_cos();
+    let (sin, cos) = (sin.abs(), cos.abs());
+    let (w, h) = (src_w as f64, src_h as f64);
+    // Ignore float noise so right angles do not gain a pixel
+    let side = |len: f64| ((len - 1e-6).ceil() as u32).max(1);
+    (side(w * cos + h * sin), side(w * sin + h * cos))
+}
+
+/// Top left corner of a `crop` sized window in `src`. A focal point is kept as close to
+/// the window center as the image bounds allow, otherwise `gravity` picks the edges.
+pub fn crop_origin(
+    (src_w, src_h): (u32, u32),
+    (crop_w, crop_h): (u32, u32),
+    gravity: Gravity,
+    focal: Option<FocalPoint>,
+) -> (u32, u32) {
+    let (free_w, free_h) = (src_w.saturating_sub(crop_w), src_h.saturating_sub(crop_h));
+    if let Some(focal) = focal {
+        let place = |at: f32, src: u32, crop: u32, free: u32| {
+            let center = at.clamp(0.0, 1.0) as f64 * src as f64;
+            ((center - crop as f64 / 2.0).round().max(0.0) as u32).min(free)
+        };
+        return (
+            place(focal.x, src_w, crop_w, free_w),
+            place(focal.y, src_h, crop_h, free_h),
+        );
+    }
+
+    // Fraction of the free space left of and above the window
+    let (fx, fy) = match gravity {
+        Gravity::Center => (1, 1),
+        Gravity::North => (1, 0),
+        Gravity::NorthEast => (2, 0),
+        Gravity::East => (2, 1),
+        Gravity::SouthEast => (2, 2),
+        Gravity::South => (1, 2),
+        Gravity::SouthWest => (0, 2),
+        Gravity::West => (0, 1),
+        Gravity::NorthWest => (0, 0),
+    };
+    (free_w * fx / 2, free_h * fy / 2)
+}
+
+/// Reject a focal point outside the 0-1 range of relative image coordinates, or NaN.
+pub fn check_focal(focal: Option<FocalPoint>) -> Result<(), EngineError> {
+    let unit = |v: f32| (0.0..=1.0).contains(&v);
+    match focal {
+        Some(FocalPoint { x, y }) if !unit(x) || !unit(y) => Err(EngineError::InvalidSpec(
+            format!("focal point {},{} is outside 0-1", x, y),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Region of `src` selected by `crop`, see `Crop` in abi.proto for how its fields combine.
+pub fn crop_rect((src_w, src_h): (u32, u32), crop: &Crop) -> Result<Rect, EngineError> {
+    let invalid = |reason: String| Err(EngineError::InvalidSpec(reason));
+    let unit = |v: f32| (0.0..=1.0).contains(&v);
+    check_focal(crop.focal)?;
+
+    if let Some(r) = crop.relative {
+        if ![r.x1, r.y1, r.x2, r.y2].into_iter().all(unit) || r.x2 <= r.x1 || r.y2 <= r.y1 {
+            return invalid(format!(
+                "relative crop {},{},{},{} is not a box within 0-1",
+                r.x1, r.y1, r.x2, r.y2
+            ));
+        }
+        // Round outwards so any non-empty box keeps at least one pixel
+        let (x1, y1) = (
+            (r.x1 as f64 * src_w as f64).floor() as u32,
+            (r.y1 as f64 * src_h as f64).floor() as u32,
+        );
+        let (x2, y2) = (
+            (r.x2 as f64 * src_w as f64).ceil() as u32,
+            (r.y2 as f64 * src_h as f64).ceil() as u32,
+        );
+        return Ok(Rect {
+            x: x1,
+            y: y1,
+            width: x2 - x1,
+            height: y2 - y1,
+        });
+    }
+
+    let (width, height) = if let Some(aspect) = crop.aspect {
+        if aspect.width == 0 || aspect.height == 0 {
+            return invalid(format!(
+                "crop aspect ratio {}:{} is empty",
+                aspect.width, aspect.height
+            ));
+        }
+        aspect_window((src_w, src_h), (aspect.width, aspect.height))
+    } else if crop.x2 == 0 && crop.y2 == 0 && (crop.width > 0 || crop.height > 0) {
+        if crop.width > src_w || crop.height > src_h {
+            return invalid(format!(
+                "crop window {}x{} is larger than the {}x{} image",
+                crop.width, crop.height, src_w, src_h
+            ));
+        }
+        (
+            if crop.width == 0 { src_w } else { crop.width },
+            if crop.height == 0 { src_h } else { crop.height },
+        )
+    } else {
+        if crop.x2 <= crop.x1 || crop.y2 <= crop.y1 {
+            return invalid(format!(
+                "crop box {},{},{},{} is empty",
+                crop.x1, crop.y1, crop.x2, crop.y2
+            ));
+        }
+        if crop.x2 > src_w || crop.y2 > src_h {
+            return invalid(format!(
+                "crop box {},{},{},{} is outside the {}x{} image",
+                crop.x1, crop.y1, crop.x2, crop.y2, src_w, src_h
+            ));
+        }
+        return Ok(Rect {
+            x: crop.x1,
+            y: crop.y1,
+            width: crop.x2 - crop.x1,
+            height: crop.y2 - crop.y1,
+        });
+    };
+
+    let (x, y) = crop_origin((src_w, src_h), (width, height), crop.gravity(), crop.focal);
+    Ok(Rect {
+        x,
+        y,
+        width,
+        height,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_auto_size_keeps_aspect_ratio() {
+        assert_eq!(auto_size((400, 300), 200, 0), (200, 150));
+        assert_eq!(auto_size((400, 300), 0, 30), (40, 30));
+        assert_eq!(auto_size((400, 300), 0, 0), (400, 300));
+        assert_eq!(auto_size((1000, 1), 10, 0), (10, 1));
+    }
+
+    #[test]
+    fn test_scaled_size_per_fit() {
+        let src = (400, 300);
+        assert_eq!(scaled_size(src, (200, 200), Fit::Fill), (200, 200));
+        assert_eq!(scaled_size(src, (200, 200), Fit::Contain), (200, 150));
+        assert_eq!(scaled_size(src, (200, 200), Fit::Cover), (267, 200));
+        assert_eq!(scaled_size(src, (200, 200), Fit::Inside), (200, 150));
+        assert_eq!(scaled_size(src, (200, 200), Fit::Outside), (267, 200));
+        // shrink only
+        assert_eq!(scaled_size(src, (800, 800), Fit::Inside), (400, 300));
+        assert_eq!(scaled_size(src, (800, 100), Fit::Outside), (400, 300));
+        assert_eq!(scaled_size(src, (800, 800), Fit::Contain), (800, 600));
+    }
+
+    #[test]
+    fn test_aspect_window() {
+        assert_eq!(aspect_window((400, 300), (1, 1)), (300, 300));
+        assert_eq!(aspect_window((400, 300), (16, 9)), (400, 225));
+        assert_eq!(aspect_window((400, 300), (1, 4)), (75, 300));
+    }
+
+    #[test]
+    fn test_rotated_size() {
+        assert_eq!(rotated_size((400, 300), 0.0), (400, 300));
+        assert_eq!(rotated_size((400, 300), 90.0), (300, 400));
+        assert_eq!(rotated_size((400, 300), 180.0), (400, 300));
+        assert_eq!(rotated_size((400, 300), 45.0), (495, 495));
+        assert_eq!(rotated_size((400, 300), -30.0), (497, 460));
+    }
+
+    #[test]
+    fn test_crop_origin_follows_gravity_and_focal_point() {
+        let (src, crop) = ((400, 300), (100, 100));
+        assert_eq!(crop_origin(src, crop, Gravity::Center, None), (150, 100));
+        assert_eq!(crop_origin(src, crop, Gravity::NorthWest, None), (0, 0));
+        assert_eq!(crop_origin(src, crop, Gravity::SouthEast, None), (300, 200));
+        assert_eq!(crop_origin(src, crop, Gravity::East, None), (300, 100));
+
+        let focal = |x, y| Some(FocalPoint { x, y });
+        assert_eq!(
+            crop_origin(src, crop, Gravity::Center, focal(0.5, 0.5)),
+            (150, 100)
+        );
+        assert_eq!(
+            crop_origin(src, crop, Gravity::Center, focal(0.25, 0.5)),
+            (50, 100)
+        );
+        // clamped to the image
+        assert_eq!(
+            crop_origin(src, crop, Gravity::Center, focal(0.0, 1.0)),
+            (0, 200)
+        );
+    }
+
+    #[test]
+    fn test_crop_rect_variants() {
+        let src = (400, 300);
+        let rect = |x, y, width, height| Rect {
+            x,
+            y,
+            width,
+            height,
+        };
+
+        let absolute = Crop {
+            x1: 10,
+            y1: 20,
+            x2: 110,
+            y2: 70,
+            ..Default::default()
+        };
+        assert_eq!(crop_rect(src, &absolute).unwrap(), rect(10, 20, 100, 50));
+
+        let relative = Crop {
+            relative: Some(crate::pb::abi::crop::Relative {
+                x1: 0.25,
+                y1: 0.0,
+                x2: 0.75,
+                y2: 0.5,
+            }),
+            ..Default::default()
+        };
+        assert_eq!(crop_rect(src, &relative).unwrap(), rect(100, 0, 200, 150));
+
+        let aspect = Crop {
+            aspect: Some(crate::pb::abi::crop::Aspect {
+                width: 16,
+                height: 9,
+            }),
+            gravity: Gravity::South as i32,
+            ..Default::default()
+        };
+        assert_eq!(crop_rect(src, &aspect).unwrap(), rect(0, 75, 400, 225));
+
+        let window = Crop {
+            width: 100,
+            gravity: Gravity::East as i32,
+            ..Default::default()
+        };
+        assert_eq!(crop_rect(src, &window).unwrap(), rect(300, 0, 100, 300));
+    }
+
+    #[test]
+    fn test_crop_rect_rejects_out_of_bounds() {
+        let src = (400, 300);
+        let rejected =
+            |crop: Crop| matches!(crop_rect(src, &crop), Err(EngineError::InvalidSpec(_)));
+
+        assert!(rejected(Crop::default()));
+        assert!(rejected(Crop {
+            x1: 10,
+            y1: 10,
+            x2: 500,
+            y2: 100,
+            ..Default::default()
+        }));
+        assert!(rejected(Crop {
+            x1: 50,
+            y1: 10,
+            x2: 40,
+            y2: 100,
+            ..Default::default()
+        }));
+        assert!(rejected(Crop {
+            width: 100,
+            height: 400,
+            ..Default::default()
+        }));
+        assert!(rejected(Crop {
+            relative: Some(crate::pb::abi::crop::Relative {
+                x1: 0.5,
+                y1: 0.0,
+                x2: 1.5,
+                y2: 1.0,
+            }),
+            ..Default::default()
+        }));
+        assert!(rejected(Crop {
+            aspect: Some(crate::pb::abi::crop::Aspect {
+                width: 16,
+                height: 0,
+            }),
+            ..Default::default()
+        }));
+        assert!(rejected(Crop {
+            width: 100,
+            focal: Some(FocalPoint { x: 2.0, y: 0.5 }),
+            ..Default::default()
+        }));
+        assert!(rejected(Crop {
+            width: 100,
+            focal: Some(FocalPoint {
+                x: f32::NAN,
+                y: 0.5
+            }),
+            ..Default::default()
+        }));
+    }
+}
@@ -1,13 +1,41 @@
-use std::io::Cursor;
+use std::{io::Cursor, ops::RangeInclusive};
 
-use anyhow::Result as AnyResult;
-use bytes::Bytes;
-use image::{DynamicImage, ImageFormat};
-use imageproc::drawing::Canvas;
+use anyhow::{Result as AnyResult, anyhow};
+use image::{
+    DynamicImage, GenericImageView, ImageDecoder, ImageEncoder, ImageFormat, ImageReader,
+    codecs::{png::PngEncoder, webp::WebPEncoder},
+    imageops::FilterType,
+    metadata::Orientation,
+};
+use imageproc::geometric_transformations::{Projection, warp_into};
 use lazy_static::lazy_static;
+use tracing::warn;
 
-use super::SpecTransform;
-pub struct ImageEngine(DynamicImage);
+use super::{
+    CancelToken, EngineError, ImageLimits, SpecTransform, filters,
+    geometry::{self, Rect},
+    metadata::Metadata,
+    seam_carve::SeamCarver,
+    smart_crop,
+};
+use crate::pb::abi::{encode_options, resize::Fit};
+pub struct ImageEngine {
+    image: DynamicImage,
+    metadata: Metadata,
+    limits: ImageLimits,
+    cancel: CancelToken,
+    smart_crop: Option<Rect>,
+}
+
+const JPEG_QUALITY: u8 = 75;
+const WEBP_QUALITY: u8 = 80;
+/// Largest payload of a JPEG APP segment, which must fit a 16-bit length with itself.
+const MAX_APP_SEGMENT: usize = 65533;
+
+/// Blurs beyond this do not hide any more detail, only cost more.
+const MAX_SIGMA: f32 = 250.0;
+const MAX_AMOUNT: f32 = 10.0;
+const SHARPEN_SIGMA: f32 = 0.8;
 
 lazy_static! {
     static ref WATERMARK: DynamicImage = {
@@ -17,20 +45,66 @@ lazy_static! {
     };
 }
 
-impl TryFrom<Bytes> for ImageEngine {
-    type Error = anyhow::Error;
+impl ImageEngine {
+    /// Decode `data`, rejecting images over `limits` from the header before decoding pixels.
+    /// This is the only way to build an engine, so the configured limits always apply.
+    /// With `auto_orient`, the image is rotated upright per its EXIF orientation.
+    pub fn decode(
+        data: &[u8],
+        limits: ImageLimits,
+        auto_orient: bool,
+    ) -> Result<Self, EngineError> {
+        let mut reader = ImageReader::new(Cursor::new(data)).with_guessed_format()?;
+        reader.limits(limits.decoder_limits());
+
+        let mut decoder = reader.into_decoder()?;
+        let mut metadata = Metadata::read(&mut decoder);
+        let orientation = match auto_orient {
+            true => decoder.orientation().unwrap_or(Orientation::NoTransforms),
+            false => Orientation::NoTransforms,
+        };
+        let (width, height) = decoder.dimensions();
+        match orientation {
+            Orientation::Rotate90
+            | Orientation::Rotate270
+            | Orientation::Rotate90FlipH
+            | Orientation::Rotate270FlipH => limits.check(height, width)?,
+            _ => limits.check(width, height)?,
+        }
+
+        let mut image = DynamicImage::from_decoder(decoder)?;
+        if orientation != Orientation::NoTransforms {
+            image.apply_orientation(orientation);
+            metadata.reset_orientation();
+        }
+
+        Ok(ImageEngine {
+            image,
+            metadata,
+            limits,
+            cancel: CancelToken::default(),
+            smart_crop: None,
+        })
+    }
 
-    fn try_from(value: Bytes) -> AnyResult<Self> {
-        let img = image::load_from_memory(value.as_ref())?;
-        Ok(ImageEngine(img))
+    /// Window chosen by the last `SmartCrop`, in the coordinates of the image it cropped.
+    pub fn smart_crop(&self) -> Option<Rect> {
+        self.smart_crop
+    }
+
+    /// Stop processing with `EngineError::Cancelled` once `cancel` is tripped.
+    pub fn with_cancel(mut self, cancel: CancelToken) -> Self {
+        self.cancel = cancel;
+        self
     }
 }
 
 impl super::Engine for ImageEngine {
-    fn apply(&mut self, specs: &[crate::pb::abi::Spec]) {
+    fn apply(&mut self, specs: &[crate::pb::abi::Spec]) -> Result<(), EngineError> {
         for spec in specs.iter() {
+            self.cancel.check()?;
             match spec.data {
-                None => {}
+                None => Ok(()),
                 Some(crate::pb::abi::spec::Data::Crop(ref v)) => self.transform(v),
                 Some(crate::pb::abi::spec::Data::Resize(ref v)) => self.transform(v),
                 Some(crate::pb::abi::spec::Data::Contrast(ref v)) => self.transform(v),
@@ -38,118 +112,794 @@ impl super::Engine for ImageEngine {
                 Some(crate::pb::abi::spec::Data::Fliph(ref v)) => self.transform(v),
                 Some(crate::pb::abi::spec::Data::Flipv(ref v)) => self.transform(v),
                 Some(crate::pb::abi::spec::Data::Watermark(ref v)) => self.transform(v),
-            }
+                Some(crate::pb::abi::spec::Data::SmartCrop(ref v)) => self.transform(v),
+                Some(crate::pb::abi::spec::Data::Rotate(ref v)) => self.transform(v),
+                Some(crate::pb::abi::spec::Data::Blur(ref v)) => self.transform(v),
+                Some(crate::pb::abi::spec::Data::Sharpen(ref v)) => self.transform(v),
+                Some(crate::pb::abi::spec::Data::UnsharpMask(ref v)) => self.transform(v),
+                Some(crate::pb::abi::spec::Data::ColorAdjust(ref v)) => self.transform(v),
+                Some(crate::pb::abi::spec::Data::Tint(ref v)) => self.transform(v),
+            }?;
         }
+        Ok(())
+    }
+
+    fn has_alpha(&self) -> bool {
+        self.image.color().has_alpha()
     }
 
-    fn generate(self, format: ImageFormat) -> Vec<u8> {
+    fn generate(
+        self,
+        format: ImageFormat,
+        options: &crate::pb::abi::EncodeOptions,
+    ) -> AnyResult<Vec<u8>> {
         let mut buf = Vec::with_capacity(1024);
-        let mut writer = Cursor::new(&mut buf);
-        let img = if format == ImageFormat::Jpeg {
-            DynamicImage::ImageRgb8(self.0.to_rgb8())
-        } else {
-            self.0
+        let (icc, exif) = match options.metadata() {
+            encode_options::Metadata::Strip => (None, None),
+            encode_options::Metadata::Icc => (self.metadata.icc, None),
+            encode_options::Metadata::Exif => (self.metadata.icc, self.metadata.exif),
         };
 
-        img.write_to(&mut writer, format)
-            .expect("Failed to write image to buffer");
-        // self.0
-        //     .write_to(&mut writer, format)
-        //     .expect("Failed to write image to buffer");
-        buf
+        match format {
+            ImageFormat::Jpeg => {
+                let img = self.image.to_rgb8();
+                let mut encoder =
+                    jpeg_encoder::Encoder::new(&mut buf, options.quality_or(JPEG_QUALITY));
+                encoder.set_progressive(options.progressive);
+                if let Some(icc) = icc {
+                    encoder.add_icc_profile(&icc)?;
+                }
+                if let Some(exif) = exif {
+                    let segment = [b"Exif\0\0".as_slice(), &exif].concat();
+                    if segment.len() > MAX_APP_SEGMENT {
+                        // Splitting EXIF over several segments is not standard, drop it
+                        warn!("Skipping EXIF of {} bytes, too large for JPEG", exif.len());
+                    } else {
+                        encoder.add_app_segment(1, &segment)?;
+                    }
+                }
+                encoder.encode(
+                    img.as_raw(),
+                    img.width().try_into()?,
+                    img.height().try_into()?,
+                    jpeg_encoder::ColorType::Rgb,
+                )?;
+            }
+            ImageFormat::Png => {
+                let mut encoder = PngEncoder::new_with_quality(
+                    &mut buf,
+                    options.png_compression().into(),
+                    options.png_filter().into(),
+                );
+                if let Some(icc) = icc {
+                    encoder.set_icc_profile(icc)?;
+                }
+                self.image.write_with_encoder(encoder)?;
+            }
+            ImageFormat::WebP if !options.lossless => {
+                let quality = options.quality_or(WEBP_QUALITY) as f32;
+                let data = if self.image.color().has_alpha() {
+                    let img = self.image.to_rgba8();
+                    webp::Encoder::from_rgba(img.as_raw(), img.width(), img.height())
+                        .encode_simple(false, quality)
+                } else {
+                    let img = self.image.to_rgb8();
+                    webp::Encoder::from_rgb(img.as_raw(), img.width(), img.height())
+                        .encode_simple(false, quality)
+                }
+                .map_err(|e| anyhow!("Failed to encode webp: {:?}", e))?;
+                buf.extend_from_slice(&data);
+            }
+            _ => {
+                let img = match format {
+                    // TIFF keeps the pixel type, except gray+alpha which it cannot encode
+                    ImageFormat::Tiff => match self.image {
+                        DynamicImage::ImageLumaA8(_) => {
+                            DynamicImage::ImageRgba8(self.image.to_rgba8())
+                        }
+                        DynamicImage::ImageLumaA16(_) => {
+                            DynamicImage::ImageRgba16(self.image.to_rgba16())
+                        }
+                        image => image,
+                    },
+                    // The remaining encoders only accept 8-bit RGB(A) buffers
+                    _ if self.image.color().has_alpha() => {
+                        DynamicImage::ImageRgba8(self.image.to_rgba8())
+                    }
+                    _ => DynamicImage::ImageRgb8(self.image.to_rgb8()),
+                };
+                match (format, icc) {
+                    (ImageFormat::WebP, Some(icc)) => {
+                        let mut encoder = WebPEncoder::new_lossless(&mut buf);
+                        encoder.set_icc_profile(icc)?;
+                        img.write_with_encoder(encoder)?;
+                    }
+                    _ => img.write_to(&mut Cursor::new(&mut buf), format)?,
+                }
+            }
+        }
+
+        Ok(buf)
     }
 }
 
 impl super::SpecTransform<&crate::pb::abi::Crop> for ImageEngine {
-    fn transform(&mut self, op: &crate::pb::abi::Crop) {
-        let x1 = op.x1.min(self.0.width());
-        let y1 = op.y1.min(self.0.height());
-        let x2 = op.x2.min(self.0.width());
-        let y2 = op.y2.min(self.0.height());
+    fn transform(&mut self, op: &crate::pb::abi::Crop) -> Result<(), EngineError> {
+        let rect = geometry::crop_rect(self.image.dimensions(), op)?;
+        self.image = self.image.crop_imm(rect.x, rect.y, rect.width, rect.height);
+        Ok(())
+    }
+}
+
+impl SpecTransform<&crate::pb::abi::SmartCrop> for ImageEngine {
+    fn transform(&mut self, op: &crate::pb::abi::SmartCrop) -> Result<(), EngineError> {
+        if op.width == 0 || op.height == 0 {
+            return Ok(());
+        }
+        self.limits.check(op.width, op.height)?;
 
-        // Make sure x2 > x1 and y2 > y1
-        if x2 <= x1 || y2 <= y1 {
-            return; // Invalid crop dimensions - do nothing
+        let window = smart_crop::find_window(&self.image, (op.width, op.height));
+        self.image = self
+            .image
+            .crop_imm(window.x, window.y, window.width, window.height);
+        if (window.width, window.height) != (op.width, op.height) {
+            self.image = self
+                .image
+                .resize_exact(op.width, op.height, FilterType::CatmullRom);
         }
-        let width = x2 - x1;
-        let height = y2 - y1;
-        let cropped_img = image::imageops::crop_imm(&self.0, op.x1, op.y1, width, height);
-        self.0 = DynamicImage::ImageRgba8(cropped_img.to_image());
+        self.smart_crop = Some(window);
+        Ok(())
     }
 }
 
 impl super::SpecTransform<&crate::pb::abi::Contrast> for ImageEngine {
-    fn transform(&mut self, op: &crate::pb::abi::Contrast) {
-        self.0 = image::DynamicImage::ImageRgba8(image::imageops::contrast(&self.0, op.contrast));
+    fn transform(&mut self, op: &crate::pb::abi::Contrast) -> Result<(), EngineError> {
+        self.image =
+            image::DynamicImage::ImageRgba8(image::imageops::contrast(&self.image, op.contrast));
+        Ok(())
+    }
+}
+
+impl SpecTransform<&crate::pb::abi::ColorAdjust> for ImageEngine {
+    fn transform(&mut self, op: &crate::pb::abi::ColorAdjust) -> Result<(), EngineError> {
+        check_range("exposure", op.exposure, -10.0..=10.0)?;
+        check_range("temperature", op.temperature, -1.0..=1.0)?;
+        check_range("tint", op.tint, -1.0..=1.0)?;
+        check_range("brightness", op.brightness, -1.0..=1.0)?;
+        if op.gamma != 0.0 {
+            check_range("gamma", op.gamma, 0.1..=10.0)?;
+        }
+        check_range("saturation", op.saturation, -1.0..=1.0)?;
+        check_range("hue", op.hue, -360.0..=360.0)?;
+
+        if *op != crate::pb::abi::ColorAdjust::default() {
+            filters::adjust_colors(&mut self.image, op);
+        }
+        Ok(())
     }
 }
 
 impl super::SpecTransform<&crate::pb::abi::Resize> for ImageEngine {
-    fn transform(&mut self, op: &crate::pb::abi::Resize) {
-        match crate::pb::abi::resize::ResizeType::try_from(op.rtype).unwrap() {
+    fn transform(&mut self, op: &crate::pb::abi::Resize) -> Result<(), EngineError> {
+        let (width, height) = geometry::auto_size(self.image.dimensions(), op.width, op.height);
+        self.limits.check(width, height)?;
+        geometry::check_focal(op.focal)?;
+
+        match op.rtype() {
             crate::pb::abi::resize::ResizeType::Normal => {
-                self.0 = image::DynamicImage::ImageRgba8(image::imageops::resize(
-                    &self.0,
-                    op.width,
-                    op.height,
-                    crate::pb::abi::resize::SampleFilter::try_from(op.filter)
-                        .unwrap()
-                        .into(),
-                ));
+                let fit = op.fit();
+                if fit == Fit::Cover {
+                    // Crop to the box aspect before scaling, the scaled source of an extreme
+                    // aspect could be far larger than the limits
+                    let src = self.image.dimensions();
+                    let (w, h) = geometry::aspect_window(src, (width, height));
+                    let (x, y) = geometry::crop_origin(src, (w, h), op.gravity(), op.focal);
+                    self.image = self.image.crop_imm(x, y, w, h).resize_exact(
+                        width,
+                        height,
+                        op.filter().into(),
+                    );
+                    return Ok(());
+                }
+
+                let (w, h) = geometry::scaled_size(self.image.dimensions(), (width, height), fit);
+                let resized = self.image.resize_exact(w, h, op.filter().into());
+                self.image = match fit {
+                    Fit::Contain => {
+                        let background = op.background.to_be_bytes();
+                        let mut canvas =
+                            image::RgbaImage::from_pixel(width, height, image::Rgba(background));
+                        let (x, y) = ((width - w) / 2, (height - h) / 2);
+                        image::imageops::overlay(
+                            &mut canvas,
+                            &resized.to_rgba8(),
+                            x as i64,
+                            y as i64,
+                        );
+                        // An opaque letterbox is opaque throughout, keep it eligible for JPEG
+                        if background[3] == u8::MAX {
+                            DynamicImage::ImageRgb8(DynamicImage::from(canvas).to_rgb8())
+                        } else {
+                            canvas.into()
+                        }
+                    }
+                    _ => resized,
+                };
             }
             crate::pb::abi::resize::ResizeType::SeamCarve => {
-                // original from photon_rs: https://docs.rs/photon-rs/0.3.2/src/photon_rs/transform.rs.html#296-326
-                let (w, h) = self.0.dimensions();
-                let (diff_w, diff_h) = (w - w.min(op.width), h - h.min(op.height));
-
-                for _ in 0..diff_w {
-                    let vec_steam =
-                        imageproc::seam_carving::find_vertical_seam(&self.0.to_rgba8().into());
-                    self.0 = imageproc::seam_carving::remove_vertical_seam(
-                        &self.0.to_rgba8().into(),
-                        &vec_steam,
-                    )
-                    .into();
-                }
-                if diff_h.ne(&0_u32) {
-                    self.0 = image::imageops::rotate90(&self.0.to_rgba8()).into();
-                    for _ in 0..diff_h {
-                        let vec_steam =
-                            imageproc::seam_carving::find_vertical_seam(&self.0.to_rgba8().into());
-                        self.0 = imageproc::seam_carving::remove_vertical_seam(
-                            &self.0.to_rgba8().into(),
-                            &vec_steam,
-                        )
-                        .into();
+                let mut carver = SeamCarver::new(self.image.to_rgba8());
+                if let Some(mask) = op.mask {
+                    match mask.mode() {
+                        crate::pb::abi::resize::mask::Mode::Protect => {
+                            carver.protect(mask.x1, mask.y1, mask.x2, mask.y2)
+                        }
+                        crate::pb::abi::resize::mask::Mode::Remove => {
+                            carver.erase(mask.x1, mask.y1, mask.x2, mask.y2)
+                        }
                     }
-                    self.0 = image::imageops::rotate270(&self.0.to_rgba8()).into();
                 }
+                carver.resize(width, height, &self.cancel)?;
+                self.image = carver.to_image().into();
             }
         }
+        Ok(())
     }
 }
 
 impl super::SpecTransform<&crate::pb::abi::Filter> for ImageEngine {
-    fn transform(&mut self, op: &crate::pb::abi::Filter) {
-        let filter_type = crate::pb::abi::filter::Filter::try_from(op.filter).unwrap();
-        filter_type.apply(&mut self.0);
+    fn transform(&mut self, op: &crate::pb::abi::Filter) -> Result<(), EngineError> {
+        let filter_type = crate::pb::abi::filter::Filter::try_from(op.filter)
+            .map_err(|_| EngineError::InvalidSpec(format!("unknown filter {}", op.filter)))?;
+        filter_type.apply(&mut self.image);
+        Ok(())
+    }
+}
+
+impl SpecTransform<&crate::pb::abi::Tint> for ImageEngine {
+    fn transform(&mut self, op: &crate::pb::abi::Tint) -> Result<(), EngineError> {
+        check_range("tint opacity", op.opacity, 0.0..=1.0)?;
+        if op.color > 0xffffff {
+            return Err(EngineError::InvalidSpec(format!(
+                "tint color {:#x} is not 0xRRGGBB",
+                op.color
+            )));
+        }
+        let [_, r, g, b] = op.color.to_be_bytes();
+        crate::pb::mix_with_color(&mut self.image, image::Rgb([r, g, b]), op.opacity);
+        Ok(())
     }
 }
 
 impl SpecTransform<&crate::pb::abi::Fliph> for ImageEngine {
-    fn transform(&mut self, _op: &crate::pb::abi::Fliph) {
-        image::imageops::flip_horizontal_in_place(&mut self.0);
+    fn transform(&mut self, _op: &crate::pb::abi::Fliph) -> Result<(), EngineError> {
+        image::imageops::flip_horizontal_in_place(&mut self.image);
+        Ok(())
     }
 }
 
 impl SpecTransform<&crate::pb::abi::Flipv> for ImageEngine {
-    fn transform(&mut self, _op: &crate::pb::abi::Flipv) {
-        image::imageops::flip_vertical_in_place(&mut self.0);
+    fn transform(&mut self, _op: &crate::pb::abi::Flipv) -> Result<(), EngineError> {
+        image::imageops::flip_vertical_in_place(&mut self.image);
+        Ok(())
     }
 }
 
+impl SpecTransform<&crate::pb::abi::Rotate> for ImageEngine {
+    fn transform(&mut self, op: &crate::pb::abi::Rotate) -> Result<(), EngineError> {
+        if !op.degrees.is_finite() {
+            return Err(EngineError::InvalidSpec(format!(
+                "rotation by {} degrees",
+                op.degrees
+            )));
+        }
+
+        let (w, h) = self.image.dimensions();
+        self.image = match op.degrees.rem_euclid(360.0) {
+            0.0 => return Ok(()),
+            90.0 => {
+                self.limits.check(h, w)?;
+                self.image.rotate90()
+            }
+            180.0 => self.image.rotate180(),
+            270.0 => {
+                self.limits.check(h, w)?;
+                self.image.rotate270()
+            }
+            degrees => {
+                let (width, height) = match op.expand {
+                    true => geometry::rotated_size((w, h), degrees),
+                    false => (w, h),
+                };
+                self.limits.check(width, height)?;
+
+                // Rotate about the source center, then move it to the canvas center
+                let projection = Projection::translate(width as f32 / 2.0, height as f32 / 2.0)
+                    * Projection::rotate(degrees.to_radians())
+                    * Projection::translate(-(w as f32) / 2.0, -(h as f32) / 2.0);
+                let background = image::Rgba(op.background.to_be_bytes());
+                let mut canvas = image::RgbaImage::from_pixel(width, height, background);
+                warp_into(
+                    &self.image.to_rgba8(),
+                    &projection,
+                    op.interpolation().into(),
+                    background,
+                    &mut canvas,
+                );
+                canvas.into()
+            }
+        };
+        Ok(())
+    }
+}
+
+impl SpecTransform<&crate::pb::abi::Blur> for ImageEngine {
+    fn transform(&mut self, op: &crate::pb::abi::Blur) -> Result<(), EngineError> {
+        check_range("blur sigma", op.sigma, 0.0..=MAX_SIGMA)?;
+        if op.sigma > 0.0 {
+            self.image = filters::blur(&self.image, op.sigma);
+        }
+        Ok(())
+    }
+}
+
+impl SpecTransform<&crate::pb::abi::Sharpen> for ImageEngine {
+    fn transform(&mut self, op: &crate::pb::abi::Sharpen) -> Result<(), EngineError> {
+        check_range("sharpen amount", op.amount, 0.0..=MAX_AMOUNT)?;
+        let amount = if op.amount == 0.0 { 1.0 } else { op.amount };
+        filters::unsharp_mask(&mut self.image, SHARPEN_SIGMA, amount, 0.0);
+        Ok(())
+    }
+}
+
+impl SpecTransform<&crate::pb::abi::UnsharpMask> for ImageEngine {
+    fn transform(&mut self, op: &crate::pb::abi::UnsharpMask) -> Result<(), EngineError> {
+        check_range("unsharp mask sigma", op.sigma, 0.0..=MAX_SIGMA)?;
+        check_range("unsharp mask amount", op.amount, 0.0..=MAX_AMOUNT)?;
+        check_range("unsharp mask threshold", op.threshold, 0.0..=255.0)?;
+        if op.sigma > 0.0 && op.amount > 0.0 {
+            filters::unsharp_mask(&mut self.image, op.sigma, op.amount, op.threshold);
+        }
+        Ok(())
+    }
+}
+
+fn check_range(name: &str, value: f32, range: RangeInclusive<f32>) -> Result<(), EngineError> {
+    if !range.contains(&value) {
+        return Err(EngineError::InvalidSpec(format!(
+            "{} {} is outside {}-{}",
+            name,
+            value,
+            range.start(),
+            range.end()
+        )));
+    }
+    Ok(())
+}
+
 impl SpecTransform<&crate::pb::abi::Watermark> for ImageEngine {
-    fn transform(&mut self, op: &crate::pb::abi::Watermark) {
-        image::imageops::overlay(&mut self.0, &*WATERMARK, op.x as i64, op.y as i64);
+    fn transform(&mut self, op: &crate::pb::abi::Watermark) -> Result<(), EngineError> {
+        image::imageops::overlay(&mut self.image, &*WATERMARK, op.x as i64, op.y as i64);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Engine;
+    use crate::pb::abi;
+
+    fn gradient(width: u32, height: u32) -> ImageEngine {
+        ImageEngine {
+            image: DynamicImage::ImageRgb8(image::RgbImage::from_fn(width, height, |x, y| {
+                image::Rgb([
+                    (x * 7 % 256) as u8,
+                    (y * 5 % 256) as u8,
+                    ((x ^ y) % 256) as u8,
+                ])
+            })),
+            metadata: Metadata::default(),
+            limits: ImageLimits::default(),
+            cancel: CancelToken::default(),
+            smart_crop: None,
+        }
+    }
+
+    #[test]
+    fn test_jpeg_quality_trades_bytes_for_fidelity() {
+        let low = abi::EncodeOptions {
+            quality: 10,
+            ..Default::default()
+        };
+        let high = abi::EncodeOptions {
+            quality: 95,
+            progressive: true,
+            ..Default::default()
+        };
+        let low = gradient(64, 64).generate(ImageFormat::Jpeg, &low).unwrap();
+        let high = gradient(64, 64).generate(ImageFormat::Jpeg, &high).unwrap();
+
+        assert!(low.len() < high.len());
+        let decoded = image::load_from_memory_with_format(&high, ImageFormat::Jpeg).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (64, 64));
+    }
+
+    #[test]
+    fn test_lossless_webp_round_trips() {
+        let options = abi::EncodeOptions {
+            lossless: true,
+            ..Default::default()
+        };
+        let engine = gradient(16, 16);
+        let origin = engine.image.to_rgb8();
+        let data = engine.generate(ImageFormat::WebP, &options).unwrap();
+
+        let decoded = image::load_from_memory_with_format(&data, ImageFormat::WebP).unwrap();
+        assert_eq!(decoded.to_rgb8(), origin);
+    }
+
+    #[test]
+    fn test_tiff_encodes_gray_alpha() {
+        for image in [
+            DynamicImage::new_luma_a8(4, 4),
+            DynamicImage::new_luma_a16(4, 4),
+        ] {
+            let mut engine = gradient(1, 1);
+            engine.image = image;
+            let data = engine
+                .generate(ImageFormat::Tiff, &Default::default())
+                .unwrap();
+            let decoded = image::load_from_memory_with_format(&data, ImageFormat::Tiff).unwrap();
+            assert_eq!(decoded.dimensions(), (4, 4));
+            assert!(decoded.color().has_alpha());
+        }
+    }
+
+    #[test]
+    fn test_decode_rejects_images_over_limits() {
+        let data = gradient(100, 80)
+            .generate(ImageFormat::Png, &Default::default())
+            .unwrap();
+        let limits = ImageLimits {
+            max_width: 90,
+            ..Default::default()
+        };
+        assert!(matches!(
+            ImageEngine::decode(&data, limits, true),
+            Err(EngineError::TooLarge(100, 80))
+        ));
+
+        let limits = ImageLimits {
+            max_pixels: 100 * 80 - 1,
+            ..Default::default()
+        };
+        assert!(ImageEngine::decode(&data, limits, true).is_err());
+        assert!(ImageEngine::decode(&data, ImageLimits::default(), true).is_ok());
+    }
+
+    #[test]
+    fn test_resize_target_is_capped() {
+        let mut engine = gradient(10, 10);
+        engine.limits.max_width = 100;
+        let spec = abi::Spec::new_resize(1000, 10, abi::resize::SampleFilter::Nereast);
+
+        assert!(matches!(
+            engine.apply(&[spec]),
+            Err(EngineError::TooLarge(1000, 10))
+        ));
+    }
+
+    #[test]
+    fn test_cancelled_engine_stops() {
+        let cancel = CancelToken::default();
+        let mut engine = gradient(40, 40).with_cancel(cancel.clone());
+        let seam_carve = abi::Spec::new_resize_seam_carve(20, 20);
+        assert!(engine.apply(&[seam_carve]).is_ok());
+
+        drop(cancel.drop_guard());
+        assert!(matches!(
+            engine.apply(&[seam_carve]),
+            Err(EngineError::Cancelled)
+        ));
+    }
+
+    #[test]
+    fn test_resize_fit_modes() {
+        let resize = |fit: abi::resize::Fit, width, height| abi::Spec {
+            data: Some(abi::spec::Data::Resize(abi::Resize {
+                width,
+                height,
+                fit: fit as i32,
+                background: 0xff0000ff,
+                ..Default::default()
+            })),
+        };
+        let size = |spec| {
+            let mut engine = gradient(40, 20);
+            engine.apply(&[spec]).unwrap();
+            engine.image
+        };
+
+        let contained = size(resize(abi::resize::Fit::Contain, 20, 20));
+        assert_eq!(contained.dimensions(), (20, 20));
+        assert_eq!(contained.get_pixel(0, 0), image::Rgba([255, 0, 0, 255]));
+        // an opaque letterbox can still be served as JPEG
+        assert_eq!(
+            crate::pb::pick_format(
+                &[ImageFormat::Jpeg, ImageFormat::Png],
+                contained.color().has_alpha()
+            ),
+            ImageFormat::Jpeg
+        );
+        assert_eq!(
+            size(resize(abi::resize::Fit::Cover, 20, 20)).dimensions(),
+            (20, 20)
+        );
+        assert_eq!(
+            size(resize(abi::resize::Fit::Fill, 20, 20)).dimensions(),
+            (20, 20)
+        );
+        assert_eq!(
+            size(resize(abi::resize::Fit::Inside, 80, 10)).dimensions(),
+            (20, 10)
+        );
+        assert_eq!(
+            size(resize(abi::resize::Fit::Outside, 80, 80)).dimensions(),
+            (40, 20)
+        );
+        assert_eq!(
+            size(resize(abi::resize::Fit::Fill, 10, 0)).dimensions(),
+            (10, 5)
+        );
+
+        // focal points are validated as for Crop
+        for x in [1.5, f32::NAN] {
+            let mut cover = resize(abi::resize::Fit::Cover, 20, 20);
+            if let Some(abi::spec::Data::Resize(op)) = &mut cover.data {
+                op.focal = Some(abi::FocalPoint { x, y: 0.5 });
+            }
+            assert!(matches!(
+                gradient(40, 20).apply(&[cover]),
+                Err(EngineError::InvalidSpec(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_cover_of_extreme_aspect_stays_within_limits() {
+        let mut engine = gradient(10000, 10);
+        let cover = abi::Spec {
+            data: Some(abi::spec::Data::Resize(abi::Resize {
+                width: 2000,
+                height: 2000,
+                fit: abi::resize::Fit::Cover as i32,
+                ..Default::default()
+            })),
+        };
+        engine.apply(&[cover]).unwrap();
+        assert_eq!(engine.image.dimensions(), (2000, 2000));
+    }
+
+    #[test]
+    fn test_crop_window_follows_gravity() {
+        let mut engine = gradient(40, 20);
+        let origin = engine.image.get_pixel(30, 10);
+        let crop = abi::Spec {
+            data: Some(abi::spec::Data::Crop(abi::Crop {
+                width: 10,
+                height: 10,
+                gravity: abi::Gravity::SouthEast as i32,
+                ..Default::default()
+            })),
+        };
+        engine.apply(&[crop]).unwrap();
+        assert_eq!(engine.image.dimensions(), (10, 10));
+        assert_eq!(engine.image.get_pixel(0, 0), origin);
+    }
+
+    #[test]
+    fn test_decode_orients_and_keeps_metadata_by_policy() {
+        let encode = |metadata: abi::encode_options::Metadata| {
+            let mut engine = gradient(40, 20);
+            engine.metadata.exif = Some(crate::engine::metadata::tests::exif_chunk(6));
+            let options = abi::EncodeOptions {
+                metadata: metadata as i32,
+                ..Default::default()
+            };
+            engine.generate(ImageFormat::Jpeg, &options).unwrap()
+        };
+
+        let data = encode(abi::encode_options::Metadata::Exif);
+        let engine = ImageEngine::decode(&data, ImageLimits::default(), true).unwrap();
+        assert_eq!(engine.image.dimensions(), (20, 40));
+        // rotated once, the kept EXIF now says upright
+        assert_eq!(
+            engine.metadata.exif,
+            Some(crate::engine::metadata::tests::exif_chunk(1))
+        );
+
+        let engine = ImageEngine::decode(&data, ImageLimits::default(), false).unwrap();
+        assert_eq!(engine.image.dimensions(), (40, 20));
+
+        let data = encode(abi::encode_options::Metadata::Strip);
+        let engine = ImageEngine::decode(&data, ImageLimits::default(), true).unwrap();
+        assert_eq!(engine.image.dimensions(), (40, 20));
+        assert!(engine.metadata.exif.is_none());
+    }
+
+    #[test]
+    fn test_generate_keeps_metadata_per_format() {
+        let icc = b"not a real profile".to_vec();
+        let generate = |format, lossless, exif: Vec<u8>| {
+            let mut engine = gradient(40, 20);
+            engine.metadata = Metadata {
+                icc: Some(icc.clone()),
+                exif: Some(exif),
+            };
+            let options = abi::EncodeOptions {
+                metadata: abi::encode_options::Metadata::Exif as i32,
+                lossless,
+                ..Default::default()
+            };
+            let data = engine.generate(format, &options).unwrap();
+            ImageEngine::decode(&data, ImageLimits::default(), false)
+                .unwrap()
+                .metadata
+        };
+        let exif = crate::engine::metadata::tests::exif_chunk(1);
+
+        let jpeg = generate(ImageFormat::Jpeg, false, exif.clone());
+        assert_eq!(
+            (jpeg.icc, jpeg.exif),
+            (Some(icc.clone()), Some(exif.clone()))
+        );
+
+        // EXIF too large for an APP1 segment is left out rather than failing the image
+        let mut large = exif.clone();
+        large.resize(MAX_APP_SEGMENT, 0);
+        let jpeg = generate(ImageFormat::Jpeg, false, large);
+        assert_eq!((jpeg.icc, jpeg.exif), (Some(icc.clone()), None));
+
+        // only the color profile is written by the other encoders
+        for format in [ImageFormat::Png, ImageFormat::WebP] {
+            let metadata = generate(format, true, exif.clone());
+            assert_eq!((metadata.icc, metadata.exif), (Some(icc.clone()), None));
+        }
+    }
+
+    #[test]
+    fn test_rotate_right_angles_and_expand() {
+        let rotate = |degrees, expand| abi::Spec {
+            data: Some(abi::spec::Data::Rotate(abi::Rotate {
+                degrees,
+                expand,
+                background: 0x00ff00ff,
+                ..Default::default()
+            })),
+        };
+
+        let mut engine = gradient(40, 20);
+        let corner = engine.image.get_pixel(0, 19);
+        engine.apply(&[rotate(-270.0, false)]).unwrap();
+        assert_eq!(engine.image.dimensions(), (20, 40));
+        assert_eq!(engine.image.get_pixel(0, 0), corner);
+        // lossless rotations keep the pixel type
+        assert!(engine.image.as_rgb8().is_some());
+
+        let mut engine = gradient(40, 20);
+        engine.apply(&[rotate(45.0, true)]).unwrap();
+        assert_eq!(engine.image.dimensions(), (43, 43));
+        assert_eq!(engine.image.get_pixel(0, 0), image::Rgba([0, 255, 0, 255]));
+
+        let mut engine = gradient(40, 20);
+        engine.apply(&[rotate(30.0, false)]).unwrap();
+        assert_eq!(engine.image.dimensions(), (40, 20));
+
+        assert!(matches!(
+            gradient(4, 4).apply(&[rotate(f32::NAN, false)]),
+            Err(EngineError::InvalidSpec(_))
+        ));
+
+        // swapped sides are checked against non-square limits
+        let mut engine = gradient(40, 20);
+        engine.limits.max_height = 30;
+        assert!(matches!(
+            engine.apply(&[rotate(90.0, false)]),
+            Err(EngineError::TooLarge(_, _))
+        ));
+    }
+
+    #[test]
+    fn test_blur_and_sharpen_validate_parameters() {
+        let spec = |data| abi::Spec { data: Some(data) };
+        let mut engine = gradient(20, 20);
+        engine
+            .apply(&[
+                spec(abi::spec::Data::Blur(abi::Blur { sigma: 20.0 })),
+                spec(abi::spec::Data::Sharpen(abi::Sharpen::default())),
+                spec(abi::spec::Data::UnsharpMask(abi::UnsharpMask {
+                    sigma: 1.5,
+                    amount: 0.8,
+                    threshold: 3.0,
+                })),
+            ])
+            .unwrap();
+        assert_eq!(engine.image.dimensions(), (20, 20));
+        assert!(engine.image.as_rgb8().is_some());
+
+        for invalid in [
+            abi::spec::Data::Blur(abi::Blur { sigma: -1.0 }),
+            abi::spec::Data::Sharpen(abi::Sharpen { amount: f32::NAN }),
+            abi::spec::Data::UnsharpMask(abi::UnsharpMask {
+                sigma: 1.0,
+                amount: 1.0,
+                threshold: 300.0,
+            }),
+        ] {
+            assert!(matches!(
+                gradient(4, 4).apply(&[spec(invalid)]),
+                Err(EngineError::InvalidSpec(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_filters_apply_to_every_pixel_type() {
+        let rgba = DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(
+            4,
+            4,
+            image::Rgba([200, 120, 40, 128]),
+        ));
+        let rgb16 = DynamicImage::ImageRgb16(image::ImageBuffer::from_pixel(
+            4,
+            4,
+            image::Rgb([40000u16, 30000, 5000]),
+        ));
+        let luma_alpha = DynamicImage::ImageLumaA8(image::ImageBuffer::from_pixel(
+            4,
+            4,
+            image::LumaA([90, 128]),
+        ));
+        let filters = [
+            abi::Spec::new_filter(abi::filter::Filter::Oceanic),
+            abi::Spec::new_filter(abi::filter::Filter::Sepia),
+            abi::Spec::new_filter(abi::filter::Filter::Grayscale),
+            abi::Spec::new_filter(abi::filter::Filter::Vintage),
+            abi::Spec::new_filter(abi::filter::Filter::Duotone),
+            abi::Spec::new_filter(abi::filter::Filter::Noir),
+            abi::Spec::new_tint(0xff0000, 0.5),
+        ];
+
+        for source in [rgba, rgb16, luma_alpha] {
+            for filter in &filters {
+                let mut engine = gradient(1, 1);
+                engine.image = source.clone();
+                engine.apply(std::slice::from_ref(filter)).unwrap();
+                assert_eq!(engine.image.color(), source.color());
+                // gray stays gray under grayscale
+                if source.color().has_color() || filter != &filters[2] {
+                    assert_ne!(engine.image, source, "{:?} did nothing", filter);
+                }
+                let alpha = |image: &DynamicImage| image.get_pixel(0, 0)[3];
+                assert_eq!(alpha(&engine.image), alpha(&source));
+            }
+        }
+
+        let mut engine = gradient(4, 4);
+        let tint = engine.apply(&[abi::Spec::new_tint(0xff0000, 1.0)]);
+        assert!(tint.is_ok());
+        assert_eq!(engine.image.get_pixel(0, 0), image::Rgba([255, 0, 0, 255]));
+
+        let unknown = abi::Spec {
+            data: Some(abi::spec::Data::Filter(abi::Filter { filter: 99 })),
+        };
+        assert!(matches!(
+            gradient(4, 4).apply(&[unknown]),
+            Err(EngineError::InvalidSpec(_))
+        ));
+        assert!(matches!(
+            gradient(4, 4).apply(&[abi::Spec::new_tint(0x1000000, 0.5)]),
+            Err(EngineError::InvalidSpec(_))
+        ));
     }
 }
@@ -0,0 +1,116 @@
+use image::ImageDecoder;
+
+const ORIENTATION_TAG: u16 = 0x112;
+const SHORT: u16 = 3;
+
+/// Metadata of the source image that the output may carry over.
+#[derive(Debug, Clone, Default)]
+pub struct Metadata {
+    /// ICC color profile.
+    pub icc: Option<Vec<u8>>,
+    /// Raw EXIF chunk, a TIFF structure without the `Exif\0\0` prefix of JPEG.
+    pub exif: Option<Vec<u8>>,
+}
+
+impl Metadata {
+    /// Read what the decoder exposes. Malformed metadata is dropped rather than failing the
+    /// image, which decodes fine without it.
+    pub fn read(decoder: &mut impl ImageDecoder) -> Self {
+        Self {
+            icc: decoder.icc_profile().ok().flatten(),
+            exif: decoder.exif_metadata().ok().flatten(),
+        }
+    }
+
+    /// Record that the pixels were rotated upright, so viewers do not rotate them again.
+    pub fn reset_orientation(&mut self) {
+        if let Some(exif) = self.exif.as_mut() {
+            set_exif_orientation(exif, 1);
+        }
+    }
+}
+
+/// Overwrite the orientation tag of the first IFD in place, if there is one.
+fn set_exif_orientation(exif: &mut [u8], value: u16) {
+    let little_endian = match exif.get(..4) {
+        Some([0x49, 0x49, 42, 0]) => true,
+        Some([0x4d, 0x4d, 0, 42]) => false,
+        _ => return,
+    };
+    let u16_at = |data: &[u8], at: usize| {
+        let bytes = [*data.get(at)?, *data.get(at + 1)?];
+        Some(if little_endian {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        })
+    };
+    let Some(ifd) = exif.get(4..8).map(|b| {
+        let bytes = [b[0], b[1], b[2], b[3]];
+        if little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        }
+    }) else {
+        return;
+    };
+    let ifd = ifd as usize;
+    let Some(entries) = u16_at(exif, ifd) else {
+        return;
+    };
+
+    for i in 0..entries as usize {
+        // 12 byte entries: tag, type, count, then the value inline
+        let entry = ifd + 2 + i * 12;
+        let (Some(tag), Some(kind)) = (u16_at(exif, entry), u16_at(exif, entry + 2)) else {
+            return;
+        };
+        if tag == ORIENTATION_TAG && kind == SHORT {
+            let bytes = if little_endian {
+                value.to_le_bytes()
+            } else {
+                value.to_be_bytes()
+            };
+            if let Some(slot) = exif.get_mut(entry + 8..entry + 10) {
+                slot.copy_from_slice(&bytes);
+            }
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Big endian EXIF chunk with a copyright string and the given orientation.
+    pub(crate) fn exif_chunk(orientation: u16) -> Vec<u8> {
+        let mut exif = vec![0x4d, 0x4d, 0, 42, 0, 0, 0, 8, 0, 2];
+        // Orientation, SHORT, count 1
+        exif.extend_from_slice(&[0x01, 0x12, 0, 3, 0, 0, 0, 1]);
+        exif.extend_from_slice(&orientation.to_be_bytes());
+        exif.extend_from_slice(&[0, 0]);
+        // Copyright, ASCII, count 4, inline "(c)\0"
+        exif.extend_from_slice(&[0x82, 0x98, 0, 2, 0, 0, 0, 4]);
+        exif.extend_from_slice(b"(c)\0");
+        exif.extend_from_slice(&[0, 0, 0, 0]);
+        exif
+    }
+
+    #[test]
+    fn test_reset_orientation_rewrites_exif() {
+        let mut metadata = Metadata {
+            exif: Some(exif_chunk(6)),
+            ..Default::default()
+        };
+        metadata.reset_orientation();
+
+        assert_eq!(metadata.exif.unwrap(), exif_chunk(1));
+
+        // not a TIFF structure, left alone
+        let mut junk = b"junk".to_vec();
+        set_exif_orientation(&mut junk, 1);
+        assert_eq!(junk, b"junk");
+    }
+}
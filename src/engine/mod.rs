@@ -1,12 +1,112 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
+};
+
+use anyhow::Result as AnyResult;
 use image::ImageFormat;
 
+pub(crate) mod filters;
+pub(crate) mod geometry;
 pub(crate) mod image_engine;
+pub(crate) mod metadata;
+pub(crate) mod seam_carve;
+pub(crate) mod smart_crop;
 
 pub trait Engine {
-    fn apply(&mut self, specs: &[crate::pb::abi::Spec]);
-    fn generate(self, format: ImageFormat) -> Vec<u8>;
+    fn apply(&mut self, specs: &[crate::pb::abi::Spec]) -> Result<(), EngineError>;
+    fn has_alpha(&self) -> bool;
+    fn generate(
+        self,
+        format: ImageFormat,
+        options: &crate::pb::abi::EncodeOptions,
+    ) -> AnyResult<Vec<u8>>;
 }
 
 pub trait SpecTransform<T> {
-    fn transform(&mut self, op: T);
+    fn transform(&mut self, op: T) -> Result<(), EngineError>;
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum EngineError {
+    #[error("image dimensions {0}x{1} exceed the configured limits")]
+    TooLarge(u32, u32),
+    #[error("failed to decode image: {0}")]
+    Decode(#[from] image::ImageError),
+    #[error("failed to read image: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("processing was cancelled")]
+    Cancelled,
+    #[error("{0}")]
+    InvalidSpec(String),
+}
+
+/// Cooperative cancellation flag, checked by transforms between operations and iterations.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn check(&self) -> Result<(), EngineError> {
+        if self.0.load(Ordering::Relaxed) {
+            return Err(EngineError::Cancelled);
+        }
+        Ok(())
+    }
+
+    /// Cancel once the returned guard is dropped, e.g. with the future awaiting the work.
+    pub fn drop_guard(&self) -> CancelOnDrop {
+        CancelOnDrop(self.clone())
+    }
+}
+
+pub struct CancelOnDrop(CancelToken);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+
+/// Caps on image dimensions, applied to decoded sources and to requested output sizes.
+#[derive(Debug, Clone, Copy)]
+pub struct ImageLimits {
+    pub max_width: u32,
+    pub max_height: u32,
+    pub max_pixels: u64,
+    /// Upper bound on decoder allocations in bytes.
+    pub max_alloc: u64,
+}
+
+impl Default for ImageLimits {
+    fn default() -> Self {
+        Self {
+            max_width: 10_000,
+            max_height: 10_000,
+            max_pixels: 40_000_000,
+            max_alloc: 512 * 1024 * 1024,
+        }
+    }
+}
+
+impl ImageLimits {
+    pub fn check(&self, width: u32, height: u32) -> Result<(), EngineError> {
+        if width > self.max_width
+            || height > self.max_height
+            || width as u64 * height as u64 > self.max_pixels
+        {
+            return Err(EngineError::TooLarge(width, height));
+        }
+        Ok(())
+    }
+
+    /// Dimensions are checked by `check` for a clearer error, the decoder only caps allocations.
+    fn decoder_limits(&self) -> image::Limits {
+        let mut limits = image::Limits::default();
+        limits.max_alloc = Some(self.max_alloc);
+        limits
+    }
 }
@@ -0,0 +1,461 @@
+use image::{Rgba, RgbaImage};
+
+use super::{CancelToken, EngineError};
+
+/// Energy added to protected pixels and subtracted from pixels to erase, well beyond the
+/// largest gradient energy of 8 * 255^2.
+const MASK_ENERGY: i32 = 1 << 20;
+/// Seams inserted along an axis at most, as a multiple of the image size along it.
+const MAX_SEAM_GROWTH: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Axis {
+    /// Top to bottom seams, one pixel per row.
+    Vertical,
+    /// Left to right seams, one pixel per column.
+    Horizontal,
+}
+
+/// Content-aware resizing over a single RGBA buffer. The dual-gradient energy map is
+/// computed once and only refreshed around each removed seam.
+#[derive(Clone)]
+pub struct SeamCarver {
+    width: usize,
+    height: usize,
+    pixels: Vec<Rgba<u8>>,
+    /// Gradient energy plus mask bias of every pixel.
+    energy: Vec<i32>,
+    bias: Vec<i32>,
+    /// Original column of every pixel, only tracked while choosing seams to insert.
+    origin: Vec<u32>,
+}
+
+impl SeamCarver {
+    pub fn new(image: RgbaImage) -> Self {
+        let (width, height) = (image.width() as usize, image.height() as usize);
+        let mut carver = Self {
+            width,
+            height,
+            pixels: image.pixels().copied().collect(),
+            energy: vec![],
+            bias: vec![0; width * height],
+            origin: vec![],
+        };
+        carver.compute_energy();
+        carver
+    }
+
+    /// Keep seams out of the pixels in `x1..x2, y1..y2`.
+    pub fn protect(&mut self, x1: u32, y1: u32, x2: u32, y2: u32) {
+        self.mask(x1, y1, x2, y2, MASK_ENERGY);
+    }
+
+    /// Route seams through the pixels in `x1..x2, y1..y2` first.
+    pub fn erase(&mut self, x1: u32, y1: u32, x2: u32, y2: u32) {
+        self.mask(x1, y1, x2, y2, -MASK_ENERGY);
+    }
+
+    /// Carve to `width` x `height`. Vertical and horizontal seams are removed interleaved,
+    /// cheapest first, then duplicated where the target is larger than the image.
+    pub fn resize(
+        &mut self,
+        width: u32,
+        height: u32,
+        cancel: &CancelToken,
+    ) -> Result<(), EngineError> {
+        let (width, height) = ((width as usize).max(1), (height as usize).max(1));
+
+        while self.width > width || self.height > height {
+            cancel.check()?;
+            let vertical = (self.width > width).then(|| self.find_seam(Axis::Vertical));
+            let horizontal = (self.height > height).then(|| self.find_seam(Axis::Horizontal));
+            let (axis, seam) = match (vertical, horizontal) {
+                // Compare mean energies, the seams differ in length
+                (Some((v, v_cost)), Some((h, h_cost))) => {
+                    if v_cost * h.len() as i64 <= h_cost * v.len() as i64 {
+                        (Axis::Vertical, v)
+                    } else {
+                        (Axis::Horizontal, h)
+                    }
+                }
+                (Some((v, _)), None) => (Axis::Vertical, v),
+                (None, Some((h, _))) => (Axis::Horizontal, h),
+                (None, None) => break,
+            };
+            self.remove_seam(axis, &seam);
+        }
+
+        if self.width < width {
+            self.insert_vertical_seams(width - self.width, cancel)?;
+        }
+        if self.height < height {
+            self.transpose();
+            let inserted = self.insert_vertical_seams(height - self.width, cancel);
+            self.transpose();
+            inserted?;
+        }
+        Ok(())
+    }
+
+    pub fn to_image(&self) -> RgbaImage {
+        RgbaImage::from_fn(self.width as u32, self.height as u32, |x, y| {
+            self.pixels[y as usize * self.width + x as usize]
+        })
+    }
+
+    fn mask(&mut self, x1: u32, y1: u32, x2: u32, y2: u32, bias: i32) {
+        let (x2, y2) = (
+            (x2 as usize).min(self.width),
+            (y2 as usize).min(self.height),
+        );
+        for y in y1 as usize..y2 {
+            for x in x1 as usize..x2 {
+                let i = y * self.width + x;
+                self.bias[i] = bias;
+                self.energy[i] = self.pixel_energy(x, y);
+            }
+        }
+    }
+
+    fn compute_energy(&mut self) {
+        self.energy = (0..self.width * self.height)
+            .map(|i| self.pixel_energy(i % self.width, i / self.width))
+            .collect();
+    }
+
+    /// Squared colour differences of the horizontal and vertical neighbours, clamped at
+    /// edges, plus the mask bias.
+    fn pixel_energy(&self, x: usize, y: usize) -> i32 {
+        let at = |x: usize, y: usize| self.pixels[y * self.width + x].0;
+        let diff = |a: [u8; 4], b: [u8; 4]| -> i32 {
+            a.iter()
+                .zip(b.iter())
+                .map(|(&a, &b)| (a as i32 - b as i32).pow(2))
+                .sum()
+        };
+        let (left, right) = (x.saturating_sub(1), (x + 1).min(self.width - 1));
+        let (up, down) = (y.saturating_sub(1), (y + 1).min(self.height - 1));
+        diff(at(left, y), at(right, y))
+            + diff(at(x, up), at(x, down))
+            + self.bias[y * self.width + x]
+    }
+
+    /// Length of a seam along `axis` and the number of positions it can take.
+    fn extent(&self, axis: Axis) -> (usize, usize) {
+        match axis {
+            Axis::Vertical => (self.height, self.width),
+            Axis::Horizontal => (self.width, self.height),
+        }
+    }
+
+    /// Buffer index of seam step `i` at position `j`.
+    fn index(&self, axis: Axis, i: usize, j: usize) -> usize {
+        match axis {
+            Axis::Vertical => i * self.width + j,
+            Axis::Horizontal => j * self.width + i,
+        }
+    }
+
+    /// Lowest cumulative energy seam along `axis`, with its total energy.
+    fn find_seam(&self, axis: Axis) -> (Vec<usize>, i64) {
+        let (len, span) = self.extent(axis);
+        let mut cost: Vec<i64> = (0..span)
+            .map(|j| self.energy[self.index(axis, 0, j)] as i64)
+            .collect();
+        let mut next = vec![0_i64; span];
+        // Position offset (-1, 0, 1) of the previous step for every pixel after the first
+        let mut parent = vec![0_i8; len * span];
+
+        for i in 1..len {
+            for j in 0..span {
+                let (mut best, mut offset) = (cost[j], 0);
+                if j > 0 && cost[j - 1] < best {
+                    (best, offset) = (cost[j - 1], -1);
+                }
+                if j + 1 < span && cost[j + 1] < best {
+                    (best, offset) = (cost[j + 1], 1);
+                }
+                next[j] = best + self.energy[self.index(axis, i, j)] as i64;
+                parent[i * span + j] = offset;
+            }
+            std::mem::swap(&mut cost, &mut next);
+        }
+
+        let mut j = (0..span).min_by_key(|&j| cost[j]).unwrap_or(0);
+        let total = cost[j];
+        let mut seam = vec![0; len];
+        for i in (0..len).rev() {
+            seam[i] = j;
+            j = (j as isize + parent[i * span + j] as isize) as usize;
+        }
+        (seam, total)
+    }
+
+    fn remove_seam(&mut self, axis: Axis, seam: &[usize]) {
+        let (w, h) = (self.width, self.height);
+        compact(&mut self.pixels, axis, seam, w, h);
+        compact(&mut self.energy, axis, seam, w, h);
+        compact(&mut self.bias, axis, seam, w, h);
+        if !self.origin.is_empty() {
+            compact(&mut self.origin, axis, seam, w, h);
+        }
+        match axis {
+            Axis::Vertical => self.width -= 1,
+            Axis::Horizontal => self.height -= 1,
+        }
+
+        // Only pixels that gained a new horizontal or vertical neighbour change energy
+        let (_, span) = self.extent(axis);
+        for (i, &s) in seam.iter().enumerate() {
+            for j in s.saturating_sub(2)..(s + 2).min(span) {
+                let k = self.index(axis, i, j);
+                self.energy[k] = self.pixel_energy(k % self.width, k / self.width);
+            }
+        }
+    }
+
+    /// Widen by `count` columns by duplicating the cheapest seams. A round inserts at most
+    /// half the current width, so the same seam is not stretched over and over.
+    fn insert_vertical_seams(
+        &mut self,
+        mut count: usize,
+        cancel: &CancelToken,
+    ) -> Result<(), EngineError> {
+        // Each round inserts at most half the width, which never grows from zero
+        if self.width == 0 || count > self.width * MAX_SEAM_GROWTH {
+            return Err(EngineError::InvalidSpec(format!(
+                "cannot insert {} seams into an image {} pixels across",
+                count, self.width
+            )));
+        }
+        while count > 0 {
+            let round = count.min(self.width.div_ceil(2));
+            let (w, h) = (self.width, self.height);
+
+            // Pick the seams by removing them from a copy that remembers original columns
+            let mut trial = self.clone();
+            trial.origin = (0..w * h).map(|i| (i % w) as u32).collect();
+            let mut chosen = vec![vec![]; h];
+            for _ in 0..round {
+                cancel.check()?;
+                let (seam, _) = trial.find_seam(Axis::Vertical);
+                for (y, &x) in seam.iter().enumerate() {
+                    chosen[y].push(trial.origin[y * trial.width + x] as usize);
+                }
+                trial.remove_seam(Axis::Vertical, &seam);
+            }
+
+            let mut pixels = Vec::with_capacity((w + round) * h);
+            let mut bias = Vec::with_capacity((w + round) * h);
+            for (y, columns) in chosen.iter_mut().enumerate() {
+                columns.sort_unstable();
+                let mut columns = columns.iter().peekable();
+                for x in 0..w {
+                    let i = y * w + x;
+                    pixels.push(self.pixels[i]);
+                    bias.push(self.bias[i]);
+                    if columns.next_if_eq(&&x).is_some() {
+                        // Blend with the right neighbour, erased areas are not grown
+                        let right = self.pixels[y * w + (x + 1).min(w - 1)];
+                        pixels.push(average(self.pixels[i], right));
+                        bias.push(self.bias[i].max(0));
+                    }
+                }
+            }
+            (self.pixels, self.bias) = (pixels, bias);
+            self.width += round;
+            self.compute_energy();
+            count -= round;
+        }
+        Ok(())
+    }
+
+    /// Swap rows and columns, so horizontal seams can be inserted as vertical ones.
+    fn transpose(&mut self) {
+        let (w, h) = (self.width, self.height);
+        fn transposed<T: Copy>(buf: &[T], w: usize, h: usize) -> Vec<T> {
+            (0..w * h).map(|i| buf[(i % h) * w + i / h]).collect()
+        }
+        self.pixels = transposed(&self.pixels, w, h);
+        self.energy = transposed(&self.energy, w, h);
+        self.bias = transposed(&self.bias, w, h);
+        (self.width, self.height) = (h, w);
+    }
+}
+
+/// Remove the `seam` pixels from a `w` x `h` row-major buffer in place.
+fn compact<T: Copy>(buf: &mut Vec<T>, axis: Axis, seam: &[usize], w: usize, h: usize) {
+    match axis {
+        Axis::Vertical => {
+            // Every row shifts left by its index, plus one after the removed pixel
+            for (y, &sx) in seam.iter().enumerate() {
+                let (src, dst) = (y * w, y * (w - 1));
+                buf.copy_within(src..src + sx, dst);
+                buf.copy_within(src + sx + 1..src + w, dst + sx);
+            }
+            buf.truncate((w - 1) * h);
+        }
+        Axis::Horizontal => {
+            // Every column shifts up below the removed pixel, leaving the last row spare
+            for (x, &sy) in seam.iter().enumerate() {
+                for y in sy..h - 1 {
+                    buf[y * w + x] = buf[(y + 1) * w + x];
+                }
+            }
+            buf.truncate(w * (h - 1));
+        }
+    }
+}
+
+fn average(a: Rgba<u8>, b: Rgba<u8>) -> Rgba<u8> {
+    Rgba(std::array::from_fn(|c| {
+        ((a[c] as u16 + b[c] as u16) / 2) as u8
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    const WHITE: Rgba<u8> = Rgba([255, 255, 255, 255]);
+    const RED: Rgba<u8> = Rgba([255, 0, 0, 255]);
+
+    /// Flat background with 2px high-contrast stripes every 16 columns.
+    fn striped(width: u32, height: u32) -> RgbaImage {
+        RgbaImage::from_fn(width, height, |x, _| {
+            if x % 16 / 2 == 4 {
+                WHITE
+            } else {
+                Rgba([10, 20, 30, 255])
+            }
+        })
+    }
+
+    fn count(image: &RgbaImage, color: Rgba<u8>) -> usize {
+        (0..image.width())
+            .filter(|&x| *image.get_pixel(x, 0) == color)
+            .count()
+    }
+
+    #[test]
+    fn test_energy_stays_consistent_after_removal() {
+        let image = RgbaImage::from_fn(40, 30, |x, y| {
+            Rgba([
+                (x * 13 % 256) as u8,
+                (y * 7 % 256) as u8,
+                (x * y % 256) as u8,
+                255,
+            ])
+        });
+        let mut carver = SeamCarver::new(image);
+        for axis in [Axis::Vertical, Axis::Horizontal].repeat(5) {
+            let (seam, _) = carver.find_seam(axis);
+            carver.remove_seam(axis, &seam);
+        }
+
+        assert_eq!((carver.width, carver.height), (35, 25));
+        assert_eq!(carver.energy, SeamCarver::new(carver.to_image()).energy);
+    }
+
+    #[test]
+    fn test_resize_keeps_high_energy_content() {
+        let cancel = CancelToken::default();
+        let mut carver = SeamCarver::new(striped(64, 20));
+        carver.resize(48, 15, &cancel).unwrap();
+        let image = carver.to_image();
+        assert_eq!(image.dimensions(), (48, 15));
+        assert_eq!(count(&image, WHITE), 8);
+
+        // Enlarging duplicates background seams rather than stripes
+        let mut carver = SeamCarver::new(striped(64, 20));
+        carver.resize(100, 30, &cancel).unwrap();
+        let image = carver.to_image();
+        assert_eq!(image.dimensions(), (100, 30));
+        assert_eq!(count(&image, WHITE), 8);
+    }
+
+    #[test]
+    fn test_insert_rejects_empty_and_excessive_growth() {
+        let cancel = CancelToken::default();
+        let mut carver = SeamCarver::new(RgbaImage::new(0, 10));
+        assert!(matches!(
+            carver.resize(10, 10, &cancel),
+            Err(EngineError::InvalidSpec(_))
+        ));
+
+        let mut carver = SeamCarver::new(striped(10, 10));
+        assert!(matches!(
+            carver.resize(31, 10, &cancel),
+            Err(EngineError::InvalidSpec(_))
+        ));
+        assert!(matches!(
+            carver.resize(10, 31, &cancel),
+            Err(EngineError::InvalidSpec(_))
+        ));
+        carver.resize(30, 30, &cancel).unwrap();
+        assert_eq!(carver.to_image().dimensions(), (30, 30));
+    }
+
+    #[test]
+    fn test_masks_protect_and_erase_regions() {
+        let cancel = CancelToken::default();
+        let mut image = striped(64, 20);
+        for y in 0..20 {
+            for x in 0..8 {
+                image.put_pixel(x, y, RED);
+            }
+        }
+
+        let mut carver = SeamCarver::new(image.clone());
+        carver.protect(0, 0, 8, 20);
+        carver.resize(24, 20, &cancel).unwrap();
+        let carved = carver.to_image();
+        assert_eq!((count(&carved, RED), count(&carved, WHITE)), (8, 8));
+
+        let mut carver = SeamCarver::new(image);
+        carver.erase(8, 0, 10, 20);
+        carver.resize(62, 20, &cancel).unwrap();
+        assert_eq!(count(&carver.to_image(), WHITE), 6);
+    }
+
+    /// Compare against the previous per-seam imageproc implementation with
+    /// `cargo test --release -- --ignored bench_seam_carve --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_seam_carve_against_imageproc() {
+        let image = RgbaImage::from_fn(400, 300, |x, y| {
+            Rgba([
+                (x * 13 % 256) as u8,
+                (y * 7 % 256) as u8,
+                ((x ^ y) % 256) as u8,
+                255,
+            ])
+        });
+        let (width, height) = (300, 240);
+
+        let start = Instant::now();
+        let mut carver = SeamCarver::new(image.clone());
+        carver
+            .resize(width, height, &CancelToken::default())
+            .unwrap();
+        let fast = start.elapsed();
+        assert_eq!(carver.to_image().dimensions(), (width, height));
+
+        let start = Instant::now();
+        let mut old = image::DynamicImage::ImageRgba8(image);
+        for _ in 0..400 - width {
+            let seam = imageproc::seam_carving::find_vertical_seam(&old.to_rgba8());
+            old = imageproc::seam_carving::remove_vertical_seam(&old.to_rgba8(), &seam).into();
+        }
+        old = image::imageops::rotate90(&old.to_rgba8()).into();
+        for _ in 0..300 - height {
+            let seam = imageproc::seam_carving::find_vertical_seam(&old.to_rgba8());
+            old = imageproc::seam_carving::remove_vertical_seam(&old.to_rgba8(), &seam).into();
+        }
+        let slow = start.elapsed();
+
+        println!("seam carver: {:?}, imageproc: {:?}", fast, slow);
+        assert!(fast < slow);
+    }
+}
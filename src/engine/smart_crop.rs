@@ -0,0 +1,131 @@
+use image::{DynamicImage, GenericImageView, Rgb, imageops::FilterType};
+use imageproc::gradients::sobel_gradients;
+
+use super::geometry::{self, Rect};
+
+/// Scoring runs on a copy scaled down to this size on the long side.
+const ANALYSIS_SIZE: u32 = 256;
+const EDGE_WEIGHT: f64 = 1.0;
+const SKIN_WEIGHT: f64 = 1.8;
+const SATURATION_WEIGHT: f64 = 0.3;
+
+/// The `aspect_w:aspect_h` window of `image` with the highest sum of edge, skin tone and
+/// saturation scores, as large as the image allows. Ties go to the most central window.
+pub fn find_window(image: &DynamicImage, aspect: (u32, u32)) -> Rect {
+    let (w, h) = image.dimensions();
+    let (crop_w, crop_h) = geometry::aspect_window((w, h), aspect);
+
+    let scale = (ANALYSIS_SIZE as f64 / w.max(h) as f64).min(1.0);
+    let small = image
+        .resize_exact(
+            ((w as f64 * scale).round() as u32).max(1),
+            ((h as f64 * scale).round() as u32).max(1),
+            FilterType::Triangle,
+        )
+        .to_rgb8();
+    let (sw, sh) = small.dimensions();
+    let edges = sobel_gradients(&DynamicImage::ImageRgb8(small.clone()).to_luma8());
+    let max_edge = edges.pixels().map(|p| p[0]).max().unwrap_or(0).max(1) as f64;
+
+    // Summed-area table of the pixel scores, with a zero row and column in front
+    let stride = sw as usize + 1;
+    let mut table = vec![0.0_f64; stride * (sh as usize + 1)];
+    for y in 0..sh {
+        let mut row = 0.0;
+        for x in 0..sw {
+            let pixel = *small.get_pixel(x, y);
+            row += EDGE_WEIGHT * edges.get_pixel(x, y)[0] as f64 / max_edge
+                + SKIN_WEIGHT * skin(pixel)
+                + SATURATION_WEIGHT * saturation(pixel);
+            let i = (y as usize + 1) * stride + x as usize + 1;
+            table[i] = table[i - stride] + row;
+        }
+    }
+    let sum = |x: usize, y: usize, w: usize, h: usize| {
+        table[(y + h) * stride + x + w] - table[y * stride + x + w] - table[(y + h) * stride + x]
+            + table[y * stride + x]
+    };
+
+    let (win_w, win_h) = (
+        ((crop_w as f64 * scale).round() as u32).clamp(1, sw) as usize,
+        ((crop_h as f64 * scale).round() as u32).clamp(1, sh) as usize,
+    );
+    let (free_w, free_h) = (sw as usize - win_w, sh as usize - win_h);
+    let mut best = (f64::MIN, usize::MAX, 0, 0);
+    for y in 0..=free_h {
+        for x in 0..=free_w {
+            let score = sum(x, y, win_w, win_h);
+            let off_center = (2 * x).abs_diff(free_w) + (2 * y).abs_diff(free_h);
+            if score > best.0 + 1e-9 || (score > best.0 - 1e-9 && off_center < best.1) {
+                best = (score, off_center, x, y);
+            }
+        }
+    }
+
+    let place = |at: usize, free: u32| ((at as f64 / scale).round() as u32).min(free);
+    Rect {
+        x: place(best.2, w - crop_w),
+        y: place(best.3, h - crop_h),
+        width: crop_w,
+        height: crop_h,
+    }
+}
+
+/// Closeness to a typical skin tone, for pixels that are neither too dark nor too bright.
+fn skin(Rgb([r, g, b]): Rgb<u8>) -> f64 {
+    let (r, g, b) = (r as f64, g as f64, b as f64);
+    let magnitude = (r * r + g * g + b * b).sqrt();
+    let lightness = (0.2126 * r + 0.7152 * g + 0.0722 * b) / 255.0;
+    if magnitude == 0.0 || !(0.2..=0.9).contains(&lightness) {
+        return 0.0;
+    }
+    let distance = ((r / magnitude - 0.78).powi(2)
+        + (g / magnitude - 0.57).powi(2)
+        + (b / magnitude - 0.44).powi(2))
+    .sqrt();
+    (1.0 - distance / 0.2).max(0.0)
+}
+
+fn saturation(Rgb(rgb): Rgb<u8>) -> f64 {
+    let (max, min) = (*rgb.iter().max().unwrap(), *rgb.iter().min().unwrap());
+    if max == 0 {
+        return 0.0;
+    }
+    (max - min) as f64 / max as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbImage;
+
+    #[test]
+    fn test_window_follows_detail() {
+        // Flat gray with a checkered patch near the right edge
+        let image = RgbImage::from_fn(600, 200, |x, y| {
+            if (450..550).contains(&x) && (50..150).contains(&y) && (x / 5 + y / 5) % 2 == 0 {
+                Rgb([255, 255, 255])
+            } else {
+                Rgb([90, 90, 90])
+            }
+        });
+        let window = find_window(&DynamicImage::ImageRgb8(image), (1, 1));
+        assert_eq!((window.width, window.height, window.y), (200, 200, 0));
+        assert!(window.x <= 450 && window.x + 200 >= 550, "{}", window);
+    }
+
+    #[test]
+    fn test_flat_image_picks_center() {
+        let image = RgbImage::from_pixel(300, 100, Rgb([90, 90, 90]));
+        let window = find_window(&DynamicImage::ImageRgb8(image), (1, 1));
+        assert_eq!(
+            window,
+            Rect {
+                x: 100,
+                y: 0,
+                width: 100,
+                height: 100
+            }
+        );
+    }
+}
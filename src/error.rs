@@ -0,0 +1,103 @@
+use std::sync::Arc;
+
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use tracing::{error, warn};
+
+use crate::{engine::EngineError, pool::PoolError, source::SourceError};
+
+/// Cloneable so a failure can be shared by every request waiting on the same work.
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum AppError {
+    #[error("Invalid spec: {0}")]
+    InvalidSpec(String),
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+    #[error("Source image too large: {0}")]
+    PayloadTooLarge(String),
+    #[error("Unsupported source image: {0}")]
+    UnsupportedMediaType(String),
+    #[error("Failed to retrieve source image: {0}")]
+    BadGateway(String),
+    #[error("Timed out: {0}")]
+    GatewayTimeout(String),
+    #[error("Unprocessable image: {0}")]
+    Unprocessable(String),
+    #[error("Service unavailable: {0}")]
+    ServiceUnavailable(String),
+    #[error(transparent)]
+    Internal(Arc<anyhow::Error>),
+}
+
+impl From<anyhow::Error> for AppError {
+    fn from(value: anyhow::Error) -> Self {
+        AppError::Internal(Arc::new(value))
+    }
+}
+
+impl AppError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::InvalidSpec(_) => StatusCode::BAD_REQUEST,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            AppError::BadGateway(_) => StatusCode::BAD_GATEWAY,
+            AppError::GatewayTimeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            AppError::Unprocessable(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::ServiceUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl From<SourceError> for AppError {
+    fn from(value: SourceError) -> Self {
+        match value {
+            SourceError::Blocked(reason) => AppError::Forbidden(reason),
+            SourceError::TooLarge(_) => AppError::PayloadTooLarge(value.to_string()),
+            SourceError::UnsupportedContentType(_) => {
+                AppError::UnsupportedMediaType(value.to_string())
+            }
+            SourceError::Timeout => AppError::GatewayTimeout(value.to_string()),
+            SourceError::Status(_) | SourceError::TooManyRedirects | SourceError::Request(_) => {
+                AppError::BadGateway(value.to_string())
+            }
+        }
+    }
+}
+
+impl From<EngineError> for AppError {
+    fn from(value: EngineError) -> Self {
+        match value {
+            EngineError::Cancelled => AppError::GatewayTimeout(value.to_string()),
+            EngineError::InvalidSpec(reason) => AppError::InvalidSpec(reason),
+            _ => AppError::Unprocessable(value.to_string()),
+        }
+    }
+}
+
+impl From<PoolError> for AppError {
+    fn from(value: PoolError) -> Self {
+        match value {
+            PoolError::Saturated(_) => AppError::ServiceUnavailable(value.to_string()),
+            PoolError::Panicked(e) => anyhow::Error::from(e).into(),
+        }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        if let AppError::Internal(e) = &self {
+            // Keep internal details in the logs only
+            error!("{:?}", e);
+            return (status, "Internal server error").into_response();
+        }
+
+        warn!("{}", self);
+        (status, self.to_string()).into_response()
+    }
+}
@@ -0,0 +1,135 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::OnceCell;
+
+/// Deduplicates concurrent calls by key: the first caller runs the work and later callers
+/// await its outcome, errors included. A waiter only takes over when the running call is
+/// dropped before finishing, e.g. because its request went away.
+pub struct SingleFlight<K, V, E> {
+    calls: Mutex<HashMap<K, Call<V, E>>>,
+}
+
+/// Outcome of a running call, set once by whichever caller completes it.
+type Call<V, E> = Arc<OnceCell<Result<V, E>>>;
+
+impl<K: Hash + Eq + Clone, V: Clone, E: Clone> SingleFlight<K, V, E> {
+    pub fn new() -> Self {
+        Self {
+            calls: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn run<F, Fut>(&self, key: K, f: F) -> Result<V, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<V, E>>,
+    {
+        let call = self
+            .calls
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        let result = call.get_or_init(f).await.clone();
+
+        // Finished calls are forgotten, later callers go through the caches again
+        let mut calls = self.calls.lock().unwrap();
+        if calls.get(&key).is_some_and(|c| Arc::ptr_eq(c, &call)) {
+            calls.remove(&key);
+        }
+        result
+    }
+}
+
+impl<K: Hash + Eq + Clone, V: Clone, E: Clone> Default for SingleFlight<K, V, E> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    #[tokio::test]
+    async fn test_concurrent_calls_run_once() {
+        let flight = Arc::new(SingleFlight::<&str, usize, ()>::new());
+        let runs = Arc::new(AtomicUsize::new(0));
+
+        let calls = (0..8).map(|_| {
+            let (flight, runs) = (flight.clone(), runs.clone());
+            tokio::spawn(async move {
+                flight
+                    .run("key", || async {
+                        tokio::time::sleep(Duration::from_millis(50)).await;
+                        Ok(runs.fetch_add(1, Ordering::SeqCst))
+                    })
+                    .await
+            })
+        });
+        for call in calls.collect::<Vec<_>>() {
+            assert_eq!(call.await.unwrap(), Ok(0));
+        }
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+
+        // The finished call is forgotten
+        assert_eq!(flight.run("key", || async { Ok(9) }).await, Ok(9));
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_failures_run_once() {
+        let flight = Arc::new(SingleFlight::<&str, usize, &str>::new());
+        let runs = Arc::new(AtomicUsize::new(0));
+
+        let calls = (0..8).map(|_| {
+            let (flight, runs) = (flight.clone(), runs.clone());
+            tokio::spawn(async move {
+                flight
+                    .run("key", || async {
+                        runs.fetch_add(1, Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_millis(50)).await;
+                        Err("failed")
+                    })
+                    .await
+            })
+        });
+        for call in calls.collect::<Vec<_>>() {
+            assert_eq!(call.await.unwrap(), Err("failed"));
+        }
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_dropped_call_is_taken_over_by_waiter() {
+        let flight = Arc::new(SingleFlight::<&str, usize, ()>::new());
+        let first = {
+            let flight = flight.clone();
+            tokio::spawn(async move {
+                flight
+                    .run("key", || async {
+                        tokio::time::sleep(Duration::from_secs(60)).await;
+                        Ok(0)
+                    })
+                    .await
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let second = {
+            let flight = flight.clone();
+            tokio::spawn(async move { flight.run("key", || async { Ok(1) }).await })
+        };
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        first.abort();
+        assert_eq!(second.await.unwrap(), Ok(1));
+    }
+}
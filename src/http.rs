@@ -0,0 +1,57 @@
+use std::time::Duration;
+
+use axum::http::{HeaderMap, header};
+
+/// `Cache-Control` value of served images.
+pub fn cache_control(max_age: Duration, immutable: bool) -> String {
+    let mut value = format!("public, max-age={}", max_age.as_secs());
+    if immutable {
+        value.push_str(", immutable");
+    }
+    value
+}
+
+/// Whether the request's `If-None-Match` matches `etag`, using the weak comparison
+/// required for conditional GETs.
+pub fn if_none_match(headers: &HeaderMap, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_owned();
+    let etag = opaque(etag);
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|tag| tag.trim() == "*" || opaque(tag) == etag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn test_cache_control() {
+        assert_eq!(
+            cache_control(Duration::from_secs(60), false),
+            "public, max-age=60"
+        );
+        assert_eq!(
+            cache_control(Duration::from_secs(60), true),
+            "public, max-age=60, immutable"
+        );
+    }
+
+    #[test]
+    fn test_if_none_match() {
+        let headers = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::IF_NONE_MATCH, HeaderValue::from_static(value));
+            headers
+        };
+        assert!(if_none_match(&headers("\"abc\""), "\"abc\""));
+        assert!(if_none_match(&headers("\"x\", W/\"abc\""), "\"abc\""));
+        assert!(if_none_match(&headers("*"), "\"abc\""));
+        assert!(!if_none_match(&headers("\"abcd\""), "\"abc\""));
+        assert!(!if_none_match(&HeaderMap::new(), "\"abc\""));
+    }
+}
@@ -1,50 +1,200 @@
+pub(crate) mod cache;
+pub(crate) mod config;
 pub(crate) mod engine;
+pub(crate) mod error;
+pub(crate) mod flight;
+pub(crate) mod http;
 pub(crate) mod pb;
+pub(crate) mod pool;
+pub(crate) mod source;
+#[cfg(test)]
+pub(crate) mod test_util;
 
 use std::{
-    hash::{DefaultHasher, Hash, Hasher},
-    net::SocketAddr,
-    num::NonZero,
+    collections::HashSet,
     sync::{Arc, Mutex},
+    time::Duration,
 };
 
-use anyhow::Result as AnyResult;
 use axum::{
-    Router,
+    Json, Router,
     extract::{Path, State},
-    http::{HeaderMap, HeaderValue},
+    http::{HeaderMap, HeaderName, HeaderValue, StatusCode, header},
     routing::get,
 };
 use bytes::Bytes;
-use engine::Engine;
-use lru::LruCache;
-use percent_encoding::percent_decode_str;
-use reqwest::StatusCode;
-use serde::Deserialize;
+use cache::{CacheKey, CacheStats, CacheStore, Layered};
+use config::{CacheConfig, Config};
+use engine::{CancelToken, Engine, image_engine::ImageEngine};
+use error::AppError;
+use flight::SingleFlight;
+use image::ImageFormat;
+use pb::abi::ImageSpec;
+use pool::{PoolStats, WorkerPool};
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use source::{Fetcher, Freshness, Source};
 use tokio::net::TcpListener;
 use tower_http::trace::TraceLayer;
-use tracing::info;
 use tracing::instrument;
+use tracing::{info, warn};
+
+/// Upstream ETag of the source image, for debugging cache behaviour.
+const UPSTREAM_ETAG: &str = "x-upstream-etag";
+/// Window chosen by a `SmartCrop` spec, for debugging.
+const SMART_CROP: &str = "x-smart-crop";
 
 #[derive(Deserialize)]
 struct Params {
     spec: String,
     url: String,
 }
-type Cache = Arc<Mutex<LruCache<u64, Bytes>>>;
+
+#[derive(Deserialize)]
+struct SignedParams {
+    signature: String,
+    spec: String,
+    url: String,
+}
+
+#[derive(Serialize)]
+struct Stats {
+    sources: Vec<CacheStats>,
+    results: Vec<CacheStats>,
+    pool: PoolStats,
+}
+
+/// A processed image with the validators of the source it was made from, protobuf encoded
+/// in the result cache so hits are served without loading the source.
+#[derive(Clone, PartialEq, prost::Message)]
+struct Rendered {
+    #[prost(bytes = "bytes", tag = "1")]
+    data: Bytes,
+    /// `x,y,width,height` of the window picked by the last smart crop.
+    #[prost(string, optional, tag = "2")]
+    smart_crop: Option<String>,
+    /// Content hash served as the ETag, see `CacheKey::etag`.
+    #[prost(string, tag = "3")]
+    etag: String,
+    #[prost(string, optional, tag = "4")]
+    last_modified: Option<String>,
+    #[prost(string, optional, tag = "5")]
+    upstream_etag: Option<String>,
+    /// Freshness of the source, which the result shares.
+    #[prost(uint64, tag = "6")]
+    fetched_at: u64,
+    #[prost(uint64, optional, tag = "7")]
+    max_age: Option<u64>,
+    #[prost(string, tag = "8")]
+    content_type: String,
+}
+
+impl Rendered {
+    /// A result of `source` whose image is still to be filled in.
+    fn describe(source: &Source, etag: CacheKey) -> Self {
+        Self {
+            etag: etag.to_string(),
+            last_modified: source.last_modified.clone(),
+            upstream_etag: source.etag.clone(),
+            fetched_at: source.fetched_at,
+            // a `no-cache` source must be revalidated before each use of its results
+            max_age: if source.no_cache {
+                Some(0)
+            } else {
+                source.max_age
+            },
+            ..Default::default()
+        }
+    }
+
+    /// ETag, Cache-Control, Vary and validator headers of the response.
+    fn headers(&self, config: &CacheConfig) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Ok(etag) = HeaderValue::from_str(&format!("\"{}\"", self.etag)) {
+            headers.insert(header::ETAG, etag);
+        }
+        headers.insert(
+            header::CACHE_CONTROL,
+            HeaderValue::from_str(&http::cache_control(config.max_age, config.immutable)).unwrap(),
+        );
+        headers.insert(header::VARY, HeaderValue::from_static("Accept"));
+        let validators = [
+            (header::LAST_MODIFIED, &self.last_modified),
+            (HeaderName::from_static(UPSTREAM_ETAG), &self.upstream_etag),
+        ];
+        for (name, value) in validators {
+            if let Some(value) = value.as_deref().and_then(|v| HeaderValue::from_str(v).ok()) {
+                headers.insert(name, value);
+            }
+        }
+        headers
+    }
+}
+
+#[derive(Clone)]
+struct AppState {
+    sources: Arc<dyn CacheStore>,
+    results: Arc<dyn CacheStore>,
+    /// Source keys with a background revalidation in flight.
+    refreshing: Arc<Mutex<HashSet<CacheKey>>>,
+    /// Concurrent requests for the same source or result share one computation.
+    source_flights: Arc<SingleFlight<CacheKey, Source, AppError>>,
+    result_flights: Arc<SingleFlight<CacheKey, Rendered, AppError>>,
+    fetcher: Fetcher,
+    pool: WorkerPool,
+    config: Arc<Config>,
+}
+
+impl AppState {
+    fn new(config: Config) -> Self {
+        Self {
+            sources: Arc::new(
+                Layered::open(
+                    "sources",
+                    config.cache.source_max_bytes,
+                    config.cache.dir.as_deref(),
+                    config.cache.disk_source_max_bytes,
+                )
+                .expect("source cache should be opened"),
+            ),
+            results: Arc::new(
+                Layered::open(
+                    "results",
+                    config.cache.result_max_bytes,
+                    config.cache.dir.as_deref(),
+                    config.cache.disk_result_max_bytes,
+                )
+                .expect("result cache should be opened"),
+            ),
+            refreshing: Arc::default(),
+            source_flights: Arc::default(),
+            result_flights: Arc::default(),
+            fetcher: Fetcher::new(config.source.clone(), config.download.clone())
+                .expect("http client should be built"),
+            pool: WorkerPool::new(config.pool.clone()),
+            config: Arc::new(config),
+        }
+    }
+}
+
+fn app(state: AppState) -> Router {
+    Router::new()
+        .route("/image/unsafe/{spec}/{url}", get(generate_unsafe))
+        .route("/image/{signature}/{spec}/{url}", get(generate_signed))
+        .route("/image/{spec}/{url}", get(reject_unsigned))
+        .route("/cache/stats", get(cache_stats))
+        .layer(TraceLayer::new_for_http())
+        .with_state(state)
+}
 
 #[tokio::main]
 async fn main() {
     tracing_subscriber::fmt::init();
 
-    let cache: Cache = Arc::new(Mutex::new(LruCache::new(NonZero::new(1024).unwrap())));
-
-    let app = Router::new()
-        .route("/image/{spec}/{url}", get(generate))
-        .layer(TraceLayer::new_for_http())
-        .with_state(cache);
+    let config = Config::from_env();
+    let addr = config.addr;
+    let app = app(AppState::new(config));
 
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
     tracing::debug!("listening on {}", addr);
 
     let listener = TcpListener::bind(&addr).await.unwrap();
@@ -54,60 +204,423 @@ async fn main() {
         .unwrap();
 }
 
-async fn generate(
+async fn generate_signed(
+    Path(params): Path<SignedParams>,
+    State(state): State<AppState>,
+    req_headers: HeaderMap,
+) -> Result<(StatusCode, HeaderMap, Bytes), AppError> {
+    // `Path` has already percent-decoded the url, decoding again would change it
+    let verified = state.config.security.secret.as_ref().is_some_and(|secret| {
+        pb::verify(
+            secret.as_bytes(),
+            &params.signature,
+            &params.spec,
+            &params.url,
+        )
+    });
+    if !verified {
+        return Err(AppError::Forbidden("invalid signature".into()));
+    }
+
+    generate(&params.spec, &params.url, state, req_headers).await
+}
+
+async fn generate_unsafe(
     Path(params): Path<Params>,
-    State(cache): State<Cache>,
-) -> Result<(HeaderMap, Vec<u8>), StatusCode> {
-    let spec: crate::pb::abi::ImageSpec = params
-        .spec
-        .as_str()
-        .try_into()
-        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    State(state): State<AppState>,
+    req_headers: HeaderMap,
+) -> Result<(StatusCode, HeaderMap, Bytes), AppError> {
+    if !state.config.security.allow_unsafe {
+        return Err(AppError::Forbidden("unsafe urls are disabled".into()));
+    }
+
+    generate(&params.spec, &params.url, state, req_headers).await
+}
+
+async fn reject_unsigned() -> AppError {
+    AppError::Forbidden("missing signature".into())
+}
 
-    let url = percent_decode_str(&params.url).decode_utf8_lossy();
-    let data = retrieve_image(&url, cache)
+async fn generate(
+    spec: &str,
+    url: &str,
+    state: AppState,
+    req_headers: HeaderMap,
+) -> Result<(StatusCode, HeaderMap, Bytes), AppError> {
+    let budget = state.config.request_timeout;
+    // Dropping the request on timeout or client disconnect cancels its processing
+    tokio::time::timeout(budget, process(spec, url, state, req_headers))
         .await
-        .map_err(|_| StatusCode::BAD_REQUEST)?;
+        .map_err(|_| AppError::GatewayTimeout(format!("request exceeded {:?}", budget)))?
+}
 
-    let mut engine: crate::engine::image_engine::ImageEngine = data
+async fn process(
+    spec: &str,
+    url: &str,
+    state: AppState,
+    req_headers: HeaderMap,
+) -> Result<(StatusCode, HeaderMap, Bytes), AppError> {
+    let spec: ImageSpec = spec
         .try_into()
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        .map_err(|e: anyhow::Error| AppError::InvalidSpec(e.to_string()))?;
 
-    engine.apply(&spec.specs);
+    let accept = req_headers
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok());
+    let formats = spec.output_format().candidates(accept);
+    let spec_bytes = spec.encode_to_vec();
+    let key = CacheKey::result(url, &spec_bytes, &formats);
 
-    // let image = engine.generate(image::ImageFormat::Jpeg);
-    let image = engine.generate(image::ImageFormat::Png);
-    info!("Finished processing: image size {}", image.len());
+    let cached = cache::get_blocking(state.results.clone(), key).await;
+    let cached = cached
+        .and_then(|data| Rendered::decode(data).ok())
+        .filter(|r| !r.etag.is_empty());
+    let cache = &state.config.cache;
+    // Results share the freshness of their source, but no stale window: once the source is
+    // stale, its own revalidation decides whether the result can be reused
+    let fresh = |r: &Rendered| {
+        source::freshness(r.fetched_at, r.max_age, cache.source_ttl, Duration::ZERO)
+            == Freshness::Fresh
+    };
+    let rendered = match cached {
+        Some(rendered) if fresh(&rendered) => {
+            info!("Match cached result");
+            rendered
+        }
+        // Outdated or missing, check the source it was made from
+        cached => {
+            let source = retrieve_image(url, &state).await?;
+            let etag = CacheKey::etag(&source.digest, &spec_bytes, &formats);
+            let described = Rendered::describe(&source, etag);
+            if http::if_none_match(&req_headers, &format!("\"{}\"", etag)) {
+                info!("Not modified");
+                return Ok((
+                    StatusCode::NOT_MODIFIED,
+                    described.headers(cache),
+                    Bytes::new(),
+                ));
+            }
+            state
+                .result_flights
+                .run(key, || {
+                    render(key, described, cached, &spec, &formats, &source, &state)
+                })
+                .await?
+        }
+    };
 
-    let mut headers = HeaderMap::new();
-    // headers.insert("CONTENT-TYPE", HeaderValue::from_static("image/jpeg"));
-    headers.insert("CONTENT-TYPE", HeaderValue::from_static("image/png"));
+    let mut headers = rendered.headers(cache);
+    if http::if_none_match(&req_headers, &format!("\"{}\"", rendered.etag)) {
+        info!("Not modified");
+        return Ok((StatusCode::NOT_MODIFIED, headers, Bytes::new()));
+    }
+    if let Ok(content_type) = HeaderValue::from_str(&rendered.content_type) {
+        headers.insert(header::CONTENT_TYPE, content_type);
+    }
+    if let Some(window) = rendered
+        .smart_crop
+        .and_then(|v| HeaderValue::from_str(&v).ok())
+    {
+        headers.insert(SMART_CROP, window);
+    }
 
-    Ok((headers, image))
+    Ok((StatusCode::OK, headers, rendered.data))
 }
 
-#[instrument(level = "info", skip(cache))]
-async fn retrieve_image(url: &str, cache: Cache) -> AnyResult<Bytes> {
-    let mut hasher = DefaultHasher::new();
-    url.hash(&mut hasher);
-    let key = hasher.finish();
+/// Process `source` into the image `described`, reusing an earlier result with the same
+/// content hash, and store it in the result cache.
+async fn render(
+    key: CacheKey,
+    described: Rendered,
+    cached: Option<Rendered>,
+    spec: &ImageSpec,
+    formats: &[ImageFormat],
+    source: &Source,
+    state: &AppState,
+) -> Result<Rendered, AppError> {
+    let rendered = match cached.filter(|cached| cached.etag == described.etag) {
+        Some(cached) => {
+            info!("Source unchanged, reusing cached result");
+            Rendered {
+                data: cached.data,
+                smart_crop: cached.smart_crop,
+                content_type: cached.content_type,
+                ..described
+            }
+        }
+        None => {
+            let data = source.data.clone();
+            let specs = spec.specs.clone();
+            let options = spec.options.unwrap_or_default();
+            let auto_orient = spec.orients();
+            let formats = formats.to_vec();
+            let limits = state.config.image;
+            let cancel = CancelToken::default();
+            let _guard = cancel.drop_guard();
+            let (format, image, smart_crop) = state
+                .pool
+                .run(move || -> Result<_, AppError> {
+                    let mut engine =
+                        ImageEngine::decode(&data, limits, auto_orient)?.with_cancel(cancel);
+                    engine.apply(&specs)?;
+
+                    let format = pb::pick_format(&formats, engine.has_alpha());
+                    let smart_crop = engine.smart_crop();
+                    Ok((format, engine.generate(format, &options)?, smart_crop))
+                })
+                .await??;
+            info!(
+                "Finished processing: image format {:?}, size {}",
+                format,
+                image.len()
+            );
 
-    {
-        let mut guard = cache.lock().unwrap();
-        if let Some(data) = guard.get(&key) {
-            info!("Match cached {}", key);
-            return Ok(data.to_owned());
+            Rendered {
+                data: image.into(),
+                smart_crop: smart_crop.map(|rect| rect.to_string()),
+                content_type: format.to_mime_type().into(),
+                ..described
+            }
+        }
+    };
+
+    if !source.no_store {
+        cache::put_background(state.results.clone(), key, rendered.encode_to_vec().into());
+    }
+    Ok(rendered)
+}
+
+async fn cache_stats(State(state): State<AppState>) -> Json<Stats> {
+    Json(Stats {
+        sources: state.sources.stats(),
+        results: state.results.stats(),
+        pool: state.pool.stats(),
+    })
+}
+
+#[instrument(level = "info", skip(state))]
+async fn retrieve_image(url: &str, state: &AppState) -> Result<Source, AppError> {
+    let key = CacheKey::source(url);
+    state
+        .source_flights
+        .run(key, || load_source(url, key, state))
+        .await
+}
+
+async fn load_source(url: &str, key: CacheKey, state: &AppState) -> Result<Source, AppError> {
+    let cached = cache::get_blocking(state.sources.clone(), key).await;
+    let cached = cached.and_then(|data| Source::decode(data).ok());
+    let source = match cached {
+        Some(source) => {
+            let cache = &state.config.cache;
+            match source.freshness(cache.source_ttl, cache.stale_while_revalidate) {
+                Freshness::Fresh => {
+                    info!("Match cached {}", key);
+                    return Ok(source);
+                }
+                Freshness::Stale => {
+                    info!("Serve stale {}, refreshing in background", key);
+                    refresh_background(url.to_owned(), key, source.clone(), state.clone());
+                    return Ok(source);
+                }
+                Freshness::Expired => state.fetcher.revalidate(url, &source).await?,
+            }
+        }
+        // If not in cache, fetch it
+        None => state.fetcher.fetch(url).await?,
+    };
+
+    // Then update the cache, unless upstream forbids it
+    if !source.no_store {
+        cache::put_background(state.sources.clone(), key, source.encode_to_vec().into());
+    }
+
+    Ok(source)
+}
+
+/// Revalidate a stale source at most once at a time per key.
+fn refresh_background(url: String, key: CacheKey, stale: Source, state: AppState) {
+    if !state.refreshing.lock().unwrap().insert(key) {
+        return;
+    }
+    tokio::spawn(async move {
+        // Runs even if the refresh panics or the runtime drops the task
+        let _refreshing = Refreshing(&state.refreshing, key);
+        match state.fetcher.revalidate(&url, &stale).await {
+            Ok(source) if source.no_store => {}
+            Ok(source) => {
+                cache::put_background(state.sources.clone(), key, source.encode_to_vec().into())
+            }
+            Err(e) => warn!("Failed to refresh {}: {}", key, e),
+        }
+    });
+}
+
+/// Removes a key from `AppState::refreshing` when its refresh ends, however it ends.
+struct Refreshing<'a>(&'a Mutex<HashSet<CacheKey>>, CacheKey);
+
+impl Drop for Refreshing<'_> {
+    fn drop(&mut self) {
+        self.0
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::Request, response::Response};
+    use config::SecurityConfig;
+    use percent_encoding::{NON_ALPHANUMERIC, utf8_percent_encode};
+    use pool::PoolConfig;
+    use source::SourcePolicy;
+    use test_util::serve;
+    use tower::ServiceExt;
+
+    const SECRET: &str = "secret";
+
+    fn config() -> Config {
+        Config {
+            security: SecurityConfig {
+                secret: Some(SECRET.into()),
+                allow_unsafe: false,
+            },
+            source: SourcePolicy {
+                allow_private: true,
+                ..Default::default()
+            },
+            pool: PoolConfig {
+                workers: 1,
+                max_queue: 0,
+            },
+            ..Default::default()
+        }
+    }
+
+    fn spec() -> ImageSpec {
+        ImageSpec::new(vec![pb::abi::Spec::new_resize(
+            8,
+            8,
+            pb::abi::resize::SampleFilter::Triangle,
+        )])
+    }
+
+    fn signed(url: &str) -> String {
+        spec().signed_path(url, SECRET.as_bytes())
+    }
+
+    async fn request(state: &AppState, path: &str, headers: &[(HeaderName, &str)]) -> Response {
+        let mut req = Request::get(path);
+        for (name, value) in headers {
+            req = req.header(name, *value);
+        }
+        app(state.clone())
+            .oneshot(req.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_serves_images_with_validators() {
+        let url = format!("http://{}/image", serve().await);
+        let state = AppState::new(config());
+
+        let res = request(&state, &signed(&url), &[]).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let headers = res.headers();
+        let etag = headers[header::ETAG].to_str().unwrap().to_owned();
+        assert!(etag.starts_with('"') && etag.len() > 2);
+        assert_eq!(
+            headers[header::CACHE_CONTROL],
+            http::cache_control(state.config.cache.max_age, false)
+        );
+        assert_eq!(headers[header::VARY], "Accept");
+        assert_eq!(headers[UPSTREAM_ETAG], "\"v1\"");
+
+        let res = request(&state, &signed(&url), &[(header::IF_NONE_MATCH, &etag)]).await;
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(res.headers()[header::ETAG], etag.as_str());
+        assert_eq!(res.headers()[header::VARY], "Accept");
+    }
+
+    #[tokio::test]
+    async fn test_signs_urls_with_escapes() {
+        let url = format!("http://{}/image?name=a%20b%2Fc", serve().await);
+        let state = AppState::new(config());
+
+        let res = request(&state, &signed(&url), &[]).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[UPSTREAM_ETAG], "\"v1\"");
+    }
+
+    #[tokio::test]
+    async fn test_rejects_forbidden_requests() {
+        let url = format!("http://{}/image", serve().await);
+        let state = AppState::new(config());
+
+        let spec: String = (&spec()).into();
+        let url_param = utf8_percent_encode(&url, NON_ALPHANUMERIC);
+        for path in [
+            format!("/image/AAAA/{}/{}", spec, url_param),
+            format!("/image/unsafe/{}/{}", spec, url_param),
+            format!("/image/{}/{}", spec, url_param),
+        ] {
+            let res = request(&state, &path, &[]).await;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN, "{}", path);
         }
+
+        // private networks are blocked by default
+        let state = AppState::new(Config {
+            source: SourcePolicy::default(),
+            ..config()
+        });
+        let res = request(&state, &signed(&url), &[]).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
     }
 
-    // If not in cache, fetch it
-    info!("Retrieve url");
-    let resp = reqwest::get(url).await?;
-    let data = resp.bytes().await?;
+    #[tokio::test]
+    async fn test_maps_source_errors() {
+        let base = format!("http://{}", serve().await);
+
+        let mut too_small = config();
+        too_small.download.max_bytes = 16;
+        let state = AppState::new(too_small);
+        let res = request(&state, &signed(&format!("{}/image", base)), &[]).await;
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
 
-    // Then update the cache
-    let mut guard = cache.lock().unwrap();
-    guard.put(key, data.clone());
+        let state = AppState::new(config());
+        let res = request(&state, &signed(&format!("{}/text", base)), &[]).await;
+        assert_eq!(res.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
 
-    Ok(data)
+        let state = AppState::new(Config {
+            request_timeout: Duration::from_millis(100),
+            ..config()
+        });
+        let res = request(&state, &signed(&format!("{}/slow", base)), &[]).await;
+        assert_eq!(res.status(), StatusCode::GATEWAY_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn test_rejects_requests_when_pool_is_saturated() {
+        let url = format!("http://{}/image", serve().await);
+        let state = AppState::new(config());
+
+        // Hold the only worker, with no room to queue
+        let (release, blocked) = std::sync::mpsc::channel::<()>();
+        let pool = state.pool.clone();
+        let busy = tokio::spawn(async move { pool.run(move || blocked.recv()).await });
+        while state.pool.stats().busy == 0 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        let res = request(&state, &signed(&url), &[]).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        release.send(()).unwrap();
+        busy.await.unwrap().unwrap().unwrap();
+        let res = request(&state, &signed(&url), &[]).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
 }
@@ -3,10 +3,20 @@
 pub struct ImageSpec {
     #[prost(message, repeated, tag = "1")]
     pub specs: ::prost::alloc::vec::Vec<Spec>,
+    #[prost(message, optional, tag = "2")]
+    pub format: ::core::option::Option<Format>,
+    #[prost(message, optional, tag = "3")]
+    pub options: ::core::option::Option<EncodeOptions>,
+    /// Rotate the source upright per its EXIF orientation before any spec, on when unset.
+    #[prost(bool, optional, tag = "4")]
+    pub auto_orient: ::core::option::Option<bool>,
 }
 #[derive(Clone, Copy, PartialEq, ::prost::Message)]
 pub struct Spec {
-    #[prost(oneof = "spec::Data", tags = "1, 2, 3, 4, 5, 6, 7")]
+    #[prost(
+        oneof = "spec::Data",
+        tags = "1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14"
+    )]
     pub data: ::core::option::Option<spec::Data>,
 }
 /// Nested message and enum types in `Spec`.
@@ -27,8 +37,33 @@ pub mod spec {
         Filter(super::Filter),
         #[prost(message, tag = "7")]
         Watermark(super::Watermark),
+        #[prost(message, tag = "8")]
+        SmartCrop(super::SmartCrop),
+        #[prost(message, tag = "9")]
+        Rotate(super::Rotate),
+        #[prost(message, tag = "10")]
+        Blur(super::Blur),
+        #[prost(message, tag = "11")]
+        Sharpen(super::Sharpen),
+        #[prost(message, tag = "12")]
+        UnsharpMask(super::UnsharpMask),
+        #[prost(message, tag = "13")]
+        ColorAdjust(super::ColorAdjust),
+        #[prost(message, tag = "14")]
+        Tint(super::Tint),
     }
 }
+/// Point to keep closest to the center of a crop, relative to the image size with 0,0 at
+/// the top left. Takes precedence over gravity.
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct FocalPoint {
+    #[prost(float, tag = "1")]
+    pub x: f32,
+    #[prost(float, tag = "2")]
+    pub y: f32,
+}
+/// Crop region, the first one set of relative, aspect, width x height and x1,y1,x2,y2.
+/// Regions outside the image are rejected.
 #[derive(Clone, Copy, PartialEq, ::prost::Message)]
 pub struct Crop {
     #[prost(uint32, tag = "1")]
@@ -39,6 +74,52 @@ pub struct Crop {
     pub x2: u32,
     #[prost(uint32, tag = "4")]
     pub y2: u32,
+    /// Without x2 and y2, crop a width x height window placed by gravity or focal point.
+    /// A zero side keeps the whole image side.
+    #[prost(uint32, tag = "5")]
+    pub width: u32,
+    #[prost(uint32, tag = "6")]
+    pub height: u32,
+    #[prost(enumeration = "Gravity", tag = "7")]
+    pub gravity: i32,
+    #[prost(message, optional, tag = "8")]
+    pub focal: ::core::option::Option<FocalPoint>,
+    #[prost(message, optional, tag = "9")]
+    pub relative: ::core::option::Option<crop::Relative>,
+    #[prost(message, optional, tag = "10")]
+    pub aspect: ::core::option::Option<crop::Aspect>,
+}
+/// Nested message and enum types in `Crop`.
+pub mod crop {
+    /// Box relative to the image size, e.g. 0.25 for a quarter of it.
+    #[derive(Clone, Copy, PartialEq, ::prost::Message)]
+    pub struct Relative {
+        #[prost(float, tag = "1")]
+        pub x1: f32,
+        #[prost(float, tag = "2")]
+        pub y1: f32,
+        #[prost(float, tag = "3")]
+        pub x2: f32,
+        #[prost(float, tag = "4")]
+        pub y2: f32,
+    }
+    /// Largest width:height window, e.g. 16:9, placed by gravity or focal point.
+    #[derive(Clone, Copy, PartialEq, ::prost::Message)]
+    pub struct Aspect {
+        #[prost(uint32, tag = "1")]
+        pub width: u32,
+        #[prost(uint32, tag = "2")]
+        pub height: u32,
+    }
+}
+/// Crop to the width:height window with the most edges, skin tones and saturated colors,
+/// then scale it to width x height.
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct SmartCrop {
+    #[prost(uint32, tag = "1")]
+    pub width: u32,
+    #[prost(uint32, tag = "2")]
+    pub height: u32,
 }
 #[derive(Clone, Copy, PartialEq, ::prost::Message)]
 pub struct Resize {
@@ -50,9 +131,74 @@ pub struct Resize {
     pub rtype: i32,
     #[prost(enumeration = "resize::SampleFilter", tag = "4")]
     pub filter: i32,
+    #[prost(message, optional, tag = "5")]
+    pub mask: ::core::option::Option<resize::Mask>,
+    #[prost(enumeration = "resize::Fit", tag = "6")]
+    pub fit: i32,
+    /// Letterbox color of CONTAIN as 0xRRGGBBAA.
+    #[prost(uint32, tag = "7")]
+    pub background: u32,
+    /// Placement of the COVER crop.
+    #[prost(enumeration = "Gravity", tag = "8")]
+    pub gravity: i32,
+    #[prost(message, optional, tag = "9")]
+    pub focal: ::core::option::Option<FocalPoint>,
 }
 /// Nested message and enum types in `Resize`.
 pub mod resize {
+    /// Region seam carving must keep intact or erase first.
+    #[derive(Clone, Copy, PartialEq, ::prost::Message)]
+    pub struct Mask {
+        #[prost(uint32, tag = "1")]
+        pub x1: u32,
+        #[prost(uint32, tag = "2")]
+        pub y1: u32,
+        #[prost(uint32, tag = "3")]
+        pub x2: u32,
+        #[prost(uint32, tag = "4")]
+        pub y2: u32,
+        #[prost(enumeration = "mask::Mode", tag = "5")]
+        pub mode: i32,
+    }
+    /// Nested message and enum types in `Mask`.
+    pub mod mask {
+        #[derive(
+            Clone,
+            Copy,
+            Debug,
+            PartialEq,
+            Eq,
+            Hash,
+            PartialOrd,
+            Ord,
+            ::prost::Enumeration
+        )]
+        #[repr(i32)]
+        pub enum Mode {
+            Protect = 0,
+            Remove = 1,
+        }
+        impl Mode {
+            /// String value of the enum field names used in the ProtoBuf definition.
+            ///
+            /// The values are not transformed in any way and thus are considered stable
+            /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+            pub fn as_str_name(&self) -> &'static str {
+                match self {
+                    Self::Protect => "PROTECT",
+                    Self::Remove => "REMOVE",
+                }
+            }
+            /// Creates an enum from field names used in the ProtoBuf definition.
+            pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+                match value {
+                    "PROTECT" => Some(Self::Protect),
+                    "REMOVE" => Some(Self::Remove),
+                    _ => None,
+                }
+            }
+        }
+    }
     #[derive(
         Clone,
         Copy,
@@ -137,6 +283,138 @@ pub mod resize {
             }
         }
     }
+    /// How NORMAL resizes fit the image into width x height, SEAM_CARVE always carves to
+    /// the exact size. A zero dimension follows the aspect ratio in both.
+    #[derive(
+        Clone,
+        Copy,
+        Debug,
+        PartialEq,
+        Eq,
+        Hash,
+        PartialOrd,
+        Ord,
+        ::prost::Enumeration
+    )]
+    #[repr(i32)]
+    pub enum Fit {
+        /// stretch to exactly width x height
+        Fill = 0,
+        /// scale to fit inside, letterbox with background
+        Contain = 1,
+        /// scale to cover, crop the overflow
+        Cover = 2,
+        /// scale down to fit inside, never enlarge
+        Inside = 3,
+        /// scale down to cover, never enlarge
+        Outside = 4,
+    }
+    impl Fit {
+        /// String value of the enum field names used in the ProtoBuf definition.
+        ///
+        /// The values are not transformed in any way and thus are considered stable
+        /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+        pub fn as_str_name(&self) -> &'static str {
+            match self {
+                Self::Fill => "FILL",
+                Self::Contain => "CONTAIN",
+                Self::Cover => "COVER",
+                Self::Inside => "INSIDE",
+                Self::Outside => "OUTSIDE",
+            }
+        }
+        /// Creates an enum from field names used in the ProtoBuf definition.
+        pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+            match value {
+                "FILL" => Some(Self::Fill),
+                "CONTAIN" => Some(Self::Contain),
+                "COVER" => Some(Self::Cover),
+                "INSIDE" => Some(Self::Inside),
+                "OUTSIDE" => Some(Self::Outside),
+                _ => None,
+            }
+        }
+    }
+}
+/// Clockwise rotation. Multiples of 90 degrees are lossless and swap the sides as needed,
+/// other angles are resampled onto a canvas filled with background.
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct Rotate {
+    #[prost(float, tag = "1")]
+    pub degrees: f32,
+    #[prost(enumeration = "rotate::Interpolation", tag = "2")]
+    pub interpolation: i32,
+    /// Grow the canvas to fit the whole rotated image instead of keeping the source size.
+    #[prost(bool, tag = "3")]
+    pub expand: bool,
+    /// Fill of the uncovered corners as 0xRRGGBBAA.
+    #[prost(uint32, tag = "4")]
+    pub background: u32,
+}
+/// Nested message and enum types in `Rotate`.
+pub mod rotate {
+    #[derive(
+        Clone,
+        Copy,
+        Debug,
+        PartialEq,
+        Eq,
+        Hash,
+        PartialOrd,
+        Ord,
+        ::prost::Enumeration
+    )]
+    #[repr(i32)]
+    pub enum Interpolation {
+        Bilinear = 0,
+        Bicubic = 1,
+        Nearest = 2,
+    }
+    impl Interpolation {
+        /// String value of the enum field names used in the ProtoBuf definition.
+        ///
+        /// The values are not transformed in any way and thus are considered stable
+        /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+        pub fn as_str_name(&self) -> &'static str {
+            match self {
+                Self::Bilinear => "BILINEAR",
+                Self::Bicubic => "BICUBIC",
+                Self::Nearest => "NEAREST",
+            }
+        }
+        /// Creates an enum from field names used in the ProtoBuf definition.
+        pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+            match value {
+                "BILINEAR" => Some(Self::Bilinear),
+                "BICUBIC" => Some(Self::Bicubic),
+                "NEAREST" => Some(Self::Nearest),
+                _ => None,
+            }
+        }
+    }
+}
+/// Gaussian blur, sigma in pixels.
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct Blur {
+    #[prost(float, tag = "1")]
+    pub sigma: f32,
+}
+/// Light sharpening, e.g. after downscaling. amount 0 means 1.
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct Sharpen {
+    #[prost(float, tag = "1")]
+    pub amount: f32,
+}
+/// Add amount times the detail lost to a gaussian blur by sigma, skipping differences of
+/// threshold levels out of 255 or less to leave noise alone.
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct UnsharpMask {
+    #[prost(float, tag = "1")]
+    pub sigma: f32,
+    #[prost(float, tag = "2")]
+    pub amount: f32,
+    #[prost(float, tag = "3")]
+    pub threshold: f32,
 }
 #[derive(Clone, Copy, PartialEq, ::prost::Message)]
 pub struct Fliph {}
@@ -147,6 +425,31 @@ pub struct Contrast {
     #[prost(float, tag = "1")]
     pub contrast: f32,
 }
+/// Color corrections, applied in field order. Zero leaves the image unchanged.
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct ColorAdjust {
+    /// stops, -10 to 10
+    #[prost(float, tag = "1")]
+    pub exposure: f32,
+    /// -1 cooler to 1 warmer
+    #[prost(float, tag = "2")]
+    pub temperature: f32,
+    /// -1 greener to 1 more magenta
+    #[prost(float, tag = "3")]
+    pub tint: f32,
+    /// -1 to 1, added to every channel
+    #[prost(float, tag = "4")]
+    pub brightness: f32,
+    /// 0.1 to 10, above 1 brightens the midtones
+    #[prost(float, tag = "5")]
+    pub gamma: f32,
+    /// -1 grayscale to 1 twice as saturated
+    #[prost(float, tag = "6")]
+    pub saturation: f32,
+    /// rotation in degrees
+    #[prost(float, tag = "7")]
+    pub hue: f32,
+}
 #[derive(Clone, Copy, PartialEq, ::prost::Message)]
 pub struct Watermark {
     #[prost(uint32, tag = "1")]
@@ -178,6 +481,14 @@ pub mod filter {
         Oceanic = 1,
         Islands = 2,
         Marine = 3,
+        Sepia = 4,
+        Grayscale = 5,
+        /// faded, warm sepia
+        Vintage = 6,
+        /// shadows in navy, highlights in coral
+        Duotone = 7,
+        /// high contrast grayscale
+        Noir = 8,
     }
     impl Filter {
         /// String value of the enum field names used in the ProtoBuf definition.
@@ -190,6 +501,11 @@ pub mod filter {
                 Self::Oceanic => "OCEANIC",
                 Self::Islands => "ISLANDS",
                 Self::Marine => "MARINE",
+                Self::Sepia => "SEPIA",
+                Self::Grayscale => "GRAYSCALE",
+                Self::Vintage => "VINTAGE",
+                Self::Duotone => "DUOTONE",
+                Self::Noir => "NOIR",
             }
         }
         /// Creates an enum from field names used in the ProtoBuf definition.
@@ -199,8 +515,281 @@ pub mod filter {
                 "OCEANIC" => Some(Self::Oceanic),
                 "ISLANDS" => Some(Self::Islands),
                 "MARINE" => Some(Self::Marine),
+                "SEPIA" => Some(Self::Sepia),
+                "GRAYSCALE" => Some(Self::Grayscale),
+                "VINTAGE" => Some(Self::Vintage),
+                "DUOTONE" => Some(Self::Duotone),
+                "NOIR" => Some(Self::Noir),
+                _ => None,
+            }
+        }
+    }
+}
+/// Mix every pixel with color, given as 0xRRGGBB, by opacity from 0 to 1.
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct Tint {
+    #[prost(uint32, tag = "1")]
+    pub color: u32,
+    #[prost(float, tag = "2")]
+    pub opacity: f32,
+}
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct Format {
+    #[prost(enumeration = "format::Format", tag = "1")]
+    pub format: i32,
+}
+/// Nested message and enum types in `Format`.
+pub mod format {
+    #[derive(
+        Clone,
+        Copy,
+        Debug,
+        PartialEq,
+        Eq,
+        Hash,
+        PartialOrd,
+        Ord,
+        ::prost::Enumeration
+    )]
+    #[repr(i32)]
+    pub enum Format {
+        Auto = 0,
+        Png = 1,
+        Jpeg = 2,
+        Webp = 3,
+        Gif = 4,
+        Bmp = 5,
+        Tiff = 6,
+        Qoi = 7,
+    }
+    impl Format {
+        /// String value of the enum field names used in the ProtoBuf definition.
+        ///
+        /// The values are not transformed in any way and thus are considered stable
+        /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+        pub fn as_str_name(&self) -> &'static str {
+            match self {
+                Self::Auto => "AUTO",
+                Self::Png => "PNG",
+                Self::Jpeg => "JPEG",
+                Self::Webp => "WEBP",
+                Self::Gif => "GIF",
+                Self::Bmp => "BMP",
+                Self::Tiff => "TIFF",
+                Self::Qoi => "QOI",
+            }
+        }
+        /// Creates an enum from field names used in the ProtoBuf definition.
+        pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+            match value {
+                "AUTO" => Some(Self::Auto),
+                "PNG" => Some(Self::Png),
+                "JPEG" => Some(Self::Jpeg),
+                "WEBP" => Some(Self::Webp),
+                "GIF" => Some(Self::Gif),
+                "BMP" => Some(Self::Bmp),
+                "TIFF" => Some(Self::Tiff),
+                "QOI" => Some(Self::Qoi),
+                _ => None,
+            }
+        }
+    }
+}
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct EncodeOptions {
+    /// 1-100 for JPEG and lossy WebP, 0 means the encoder default
+    #[prost(uint32, tag = "1")]
+    pub quality: u32,
+    #[prost(bool, tag = "2")]
+    pub progressive: bool,
+    #[prost(enumeration = "encode_options::PngCompression", tag = "3")]
+    pub png_compression: i32,
+    #[prost(enumeration = "encode_options::PngFilter", tag = "4")]
+    pub png_filter: i32,
+    #[prost(bool, tag = "5")]
+    pub lossless: bool,
+    #[prost(enumeration = "encode_options::Metadata", tag = "6")]
+    pub metadata: i32,
+}
+/// Nested message and enum types in `EncodeOptions`.
+pub mod encode_options {
+    #[derive(
+        Clone,
+        Copy,
+        Debug,
+        PartialEq,
+        Eq,
+        Hash,
+        PartialOrd,
+        Ord,
+        ::prost::Enumeration
+    )]
+    #[repr(i32)]
+    pub enum PngCompression {
+        Fast = 0,
+        Balanced = 1,
+        Best = 2,
+    }
+    impl PngCompression {
+        /// String value of the enum field names used in the ProtoBuf definition.
+        ///
+        /// The values are not transformed in any way and thus are considered stable
+        /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+        pub fn as_str_name(&self) -> &'static str {
+            match self {
+                Self::Fast => "FAST",
+                Self::Balanced => "BALANCED",
+                Self::Best => "BEST",
+            }
+        }
+        /// Creates an enum from field names used in the ProtoBuf definition.
+        pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+            match value {
+                "FAST" => Some(Self::Fast),
+                "BALANCED" => Some(Self::Balanced),
+                "BEST" => Some(Self::Best),
                 _ => None,
             }
         }
     }
+    #[derive(
+        Clone,
+        Copy,
+        Debug,
+        PartialEq,
+        Eq,
+        Hash,
+        PartialOrd,
+        Ord,
+        ::prost::Enumeration
+    )]
+    #[repr(i32)]
+    pub enum PngFilter {
+        Adaptive = 0,
+        NoFilter = 1,
+        Sub = 2,
+        Up = 3,
+        Avg = 4,
+        Paeth = 5,
+    }
+    impl PngFilter {
+        /// String value of the enum field names used in the ProtoBuf definition.
+        ///
+        /// The values are not transformed in any way and thus are considered stable
+        /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+        pub fn as_str_name(&self) -> &'static str {
+            match self {
+                Self::Adaptive => "ADAPTIVE",
+                Self::NoFilter => "NO_FILTER",
+                Self::Sub => "SUB",
+                Self::Up => "UP",
+                Self::Avg => "AVG",
+                Self::Paeth => "PAETH",
+            }
+        }
+        /// Creates an enum from field names used in the ProtoBuf definition.
+        pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+            match value {
+                "ADAPTIVE" => Some(Self::Adaptive),
+                "NO_FILTER" => Some(Self::NoFilter),
+                "SUB" => Some(Self::Sub),
+                "UP" => Some(Self::Up),
+                "AVG" => Some(Self::Avg),
+                "PAETH" => Some(Self::Paeth),
+                _ => None,
+            }
+        }
+    }
+    /// Source metadata copied to the output. The color profile is kept by the JPEG, PNG and
+    /// lossless WebP encoders, EXIF by the JPEG encoder only.
+    #[derive(
+        Clone,
+        Copy,
+        Debug,
+        PartialEq,
+        Eq,
+        Hash,
+        PartialOrd,
+        Ord,
+        ::prost::Enumeration
+    )]
+    #[repr(i32)]
+    pub enum Metadata {
+        /// drop all metadata
+        Strip = 0,
+        /// keep the color profile only
+        Icc = 1,
+        /// keep the color profile and EXIF, e.g. copyright and camera data
+        Exif = 2,
+    }
+    impl Metadata {
+        /// String value of the enum field names used in the ProtoBuf definition.
+        ///
+        /// The values are not transformed in any way and thus are considered stable
+        /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+        pub fn as_str_name(&self) -> &'static str {
+            match self {
+                Self::Strip => "STRIP",
+                Self::Icc => "ICC",
+                Self::Exif => "EXIF",
+            }
+        }
+        /// Creates an enum from field names used in the ProtoBuf definition.
+        pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+            match value {
+                "STRIP" => Some(Self::Strip),
+                "ICC" => Some(Self::Icc),
+                "EXIF" => Some(Self::Exif),
+                _ => None,
+            }
+        }
+    }
+}
+/// Which part of an image survives a crop.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum Gravity {
+    Center = 0,
+    North = 1,
+    NorthEast = 2,
+    East = 3,
+    SouthEast = 4,
+    South = 5,
+    SouthWest = 6,
+    West = 7,
+    NorthWest = 8,
+}
+impl Gravity {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            Self::Center => "CENTER",
+            Self::North => "NORTH",
+            Self::NorthEast => "NORTH_EAST",
+            Self::East => "EAST",
+            Self::SouthEast => "SOUTH_EAST",
+            Self::South => "SOUTH",
+            Self::SouthWest => "SOUTH_WEST",
+            Self::West => "WEST",
+            Self::NorthWest => "NORTH_WEST",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "CENTER" => Some(Self::Center),
+            "NORTH" => Some(Self::North),
+            "NORTH_EAST" => Some(Self::NorthEast),
+            "EAST" => Some(Self::East),
+            "SOUTH_EAST" => Some(Self::SouthEast),
+            "SOUTH" => Some(Self::South),
+            "SOUTH_WEST" => Some(Self::SouthWest),
+            "WEST" => Some(Self::West),
+            "NORTH_WEST" => Some(Self::NorthWest),
+            _ => None,
+        }
+    }
 }
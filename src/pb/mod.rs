@@ -1,11 +1,46 @@
+#[rustfmt::skip]
 pub(crate) mod abi;
 use base64::Engine;
-use image::{DynamicImage, Rgb};
+use hmac::{Hmac, Mac};
+use image::{DynamicImage, ImageFormat, Rgb};
+use percent_encoding::{NON_ALPHANUMERIC, utf8_percent_encode};
 use prost::Message;
+use sha2::Sha256;
+
+use crate::engine::filters;
 
 impl abi::ImageSpec {
     pub fn new(specs: Vec<abi::Spec>) -> Self {
-        Self { specs }
+        Self {
+            specs,
+            format: None,
+            options: None,
+            auto_orient: None,
+        }
+    }
+
+    pub fn with_options(mut self, options: abi::EncodeOptions) -> Self {
+        self.options = Some(options);
+        self
+    }
+
+    pub fn with_format(mut self, format: abi::format::Format) -> Self {
+        self.format = Some(abi::Format {
+            format: format as i32,
+        });
+        self
+    }
+
+    /// Whether sources are rotated upright per their EXIF orientation, the default.
+    pub fn orients(&self) -> bool {
+        self.auto_orient.unwrap_or(true)
+    }
+
+    /// Output format requested by the spec, `Auto` when none was given.
+    pub fn output_format(&self) -> abi::format::Format {
+        self.format
+            .and_then(|f| abi::format::Format::try_from(f.format).ok())
+            .unwrap_or(abi::format::Format::Auto)
     }
 }
 
@@ -16,6 +51,43 @@ impl From<&abi::ImageSpec> for String {
     }
 }
 
+impl abi::ImageSpec {
+    /// Build a `/image/{signature}/{spec}/{url}` path signed with `secret`.
+    pub fn signed_path(&self, url: &str, secret: &[u8]) -> String {
+        let spec: String = self.into();
+        format!(
+            "/image/{}/{}/{}",
+            sign(secret, &spec, url),
+            spec,
+            utf8_percent_encode(url, NON_ALPHANUMERIC)
+        )
+    }
+}
+
+/// HMAC-SHA256 over `{spec}/{url}`, where `url` is the decoded source url.
+pub fn sign(secret: &[u8], spec: &str, url: &str) -> String {
+    let mac = signature_mac(secret, spec, url).finalize().into_bytes();
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(mac)
+}
+
+/// Check `signature` in constant time against the expected one for `{spec}/{url}`.
+pub fn verify(secret: &[u8], signature: &str, spec: &str, url: &str) -> bool {
+    let Ok(signature) = base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(signature) else {
+        return false;
+    };
+    signature_mac(secret, spec, url)
+        .verify_slice(&signature)
+        .is_ok()
+}
+
+fn signature_mac(secret: &[u8], spec: &str, url: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("hmac accepts keys of any size");
+    mac.update(spec.as_bytes());
+    mac.update(b"/");
+    mac.update(url.as_bytes());
+    mac
+}
+
 impl TryFrom<&str> for abi::ImageSpec {
     type Error = anyhow::Error;
     fn try_from(value: &str) -> Result<Self, Self::Error> {
@@ -37,6 +109,132 @@ impl From<abi::resize::SampleFilter> for image::imageops::FilterType {
     }
 }
 
+impl From<abi::rotate::Interpolation> for imageproc::geometric_transformations::Interpolation {
+    fn from(value: abi::rotate::Interpolation) -> Self {
+        match value {
+            abi::rotate::Interpolation::Bilinear => Self::Bilinear,
+            abi::rotate::Interpolation::Bicubic => Self::Bicubic,
+            abi::rotate::Interpolation::Nearest => Self::Nearest,
+        }
+    }
+}
+
+impl abi::format::Format {
+    /// Concrete encoder format, `None` for `Auto`.
+    pub fn image_format(self) -> Option<ImageFormat> {
+        match self {
+            abi::format::Format::Auto => None,
+            abi::format::Format::Png => Some(ImageFormat::Png),
+            abi::format::Format::Jpeg => Some(ImageFormat::Jpeg),
+            abi::format::Format::Webp => Some(ImageFormat::WebP),
+            abi::format::Format::Gif => Some(ImageFormat::Gif),
+            abi::format::Format::Bmp => Some(ImageFormat::Bmp),
+            abi::format::Format::Tiff => Some(ImageFormat::Tiff),
+            abi::format::Format::Qoi => Some(ImageFormat::Qoi),
+        }
+    }
+
+    /// Formats the client takes, in server preference order. `Auto` is
+    /// negotiated against the request's `Accept` header: WebP only when
+    /// explicitly accepted, then JPEG and PNG.
+    pub fn candidates(self, accept: Option<&str>) -> Vec<ImageFormat> {
+        if let Some(format) = self.image_format() {
+            return vec![format];
+        }
+
+        let accept = accept.unwrap_or("*/*");
+        [ImageFormat::WebP, ImageFormat::Jpeg, ImageFormat::Png]
+            .into_iter()
+            .filter(|format| {
+                // browsers send `*/*` without supporting WebP, so require it explicitly
+                let explicit = *format == ImageFormat::WebP;
+                accepts(accept, format.to_mime_type(), explicit)
+            })
+            .collect()
+    }
+}
+
+/// First candidate that keeps transparency, JPEG only if nothing else is accepted,
+/// and PNG when no candidate is left.
+pub fn pick_format(candidates: &[ImageFormat], has_alpha: bool) -> ImageFormat {
+    candidates
+        .iter()
+        .find(|format| !(has_alpha && **format == ImageFormat::Jpeg))
+        .or(candidates.first())
+        .copied()
+        .unwrap_or(ImageFormat::Png)
+}
+
+/// Whether an `Accept` header value allows `mime` with a non-zero q-value. The most
+/// specific matching range decides: the exact type, then `type/*`, then `*/*`.
+fn accepts(accept: &str, mime: &str, explicit: bool) -> bool {
+    let (kind, _) = mime.split_once('/').unwrap_or((mime, ""));
+
+    let mut best: Option<(u8, f32)> = None;
+    for item in accept.split(',') {
+        let mut parts = item.split(';').map(str::trim);
+        let range = parts.next().unwrap_or_default();
+        let q = parts
+            .filter_map(|p| p.strip_prefix("q="))
+            .find_map(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+
+        let specificity = if range.eq_ignore_ascii_case(mime) {
+            2
+        } else if explicit {
+            continue;
+        } else if range
+            .strip_suffix("/*")
+            .is_some_and(|k| k.eq_ignore_ascii_case(kind))
+        {
+            1
+        } else if range == "*/*" {
+            0
+        } else {
+            continue;
+        };
+        if best.is_none_or(|(s, _)| specificity > s) {
+            best = Some((specificity, q));
+        }
+    }
+    best.is_some_and(|(_, q)| q > 0.0)
+}
+
+impl abi::EncodeOptions {
+    /// Requested quality clamped to 1-100, or `default` when unset.
+    pub fn quality_or(&self, default: u8) -> u8 {
+        match self.quality {
+            0 => default,
+            q => q.clamp(1, 100) as u8,
+        }
+    }
+}
+
+impl From<abi::encode_options::PngCompression> for image::codecs::png::CompressionType {
+    fn from(value: abi::encode_options::PngCompression) -> Self {
+        match value {
+            abi::encode_options::PngCompression::Fast => image::codecs::png::CompressionType::Fast,
+            abi::encode_options::PngCompression::Balanced => {
+                image::codecs::png::CompressionType::Default
+            }
+            abi::encode_options::PngCompression::Best => image::codecs::png::CompressionType::Best,
+        }
+    }
+}
+
+impl From<abi::encode_options::PngFilter> for image::codecs::png::FilterType {
+    fn from(value: abi::encode_options::PngFilter) -> Self {
+        match value {
+            abi::encode_options::PngFilter::Adaptive => image::codecs::png::FilterType::Adaptive,
+            abi::encode_options::PngFilter::NoFilter => image::codecs::png::FilterType::NoFilter,
+            abi::encode_options::PngFilter::Sub => image::codecs::png::FilterType::Sub,
+            abi::encode_options::PngFilter::Up => image::codecs::png::FilterType::Up,
+            abi::encode_options::PngFilter::Avg => image::codecs::png::FilterType::Avg,
+            abi::encode_options::PngFilter::Paeth => image::codecs::png::FilterType::Paeth,
+        }
+    }
+}
+
 impl abi::filter::Filter {
     pub fn apply(self, img: &mut DynamicImage) {
         match self {
@@ -44,38 +242,45 @@ impl abi::filter::Filter {
             abi::filter::Filter::Oceanic => mix_with_color(img, Rgb([0, 89, 173]), 0.2),
             abi::filter::Filter::Islands => mix_with_color(img, Rgb([0, 24, 95]), 0.2),
             abi::filter::Filter::Marine => mix_with_color(img, Rgb([0, 14, 119]), 0.2),
+            abi::filter::Filter::Sepia => filters::map_rgb(img, sepia),
+            abi::filter::Filter::Grayscale => filters::map_rgb(img, |rgb| [filters::luma(rgb); 3]),
+            abi::filter::Filter::Vintage => {
+                // Half strength sepia with lifted blacks and dimmed whites
+                filters::map_rgb(img, |rgb| {
+                    let toned = sepia(rgb);
+                    [0, 1, 2].map(|c| 0.08 + 0.84 * (rgb[c] + toned[c]) / 2.0)
+                })
+            }
+            abi::filter::Filter::Duotone => {
+                let (shadow, highlight) = ([0.11, 0.12, 0.37], [1.0, 0.54, 0.46]);
+                filters::map_rgb(img, |rgb| {
+                    let l = filters::luma(rgb);
+                    [0, 1, 2].map(|c| shadow[c] + (highlight[c] - shadow[c]) * l)
+                })
+            }
+            abi::filter::Filter::Noir => filters::map_rgb(img, |rgb| {
+                [((filters::luma(rgb) - 0.5) * 1.6 + 0.5).clamp(0.0, 1.0); 3]
+            }),
         }
     }
 }
 
+fn sepia([r, g, b]: [f32; 3]) -> [f32; 3] {
+    [
+        0.393 * r + 0.769 * g + 0.189 * b,
+        0.349 * r + 0.686 * g + 0.168 * b,
+        0.272 * r + 0.534 * g + 0.131 * b,
+    ]
+}
+
+/// Mix every pixel with `mix_color` by `opacity`, clamped to 0-1. Alpha and the pixel type
+/// are kept.
 pub fn mix_with_color(img: &mut DynamicImage, mix_color: Rgb<u8>, opacity: f32) {
-    // 确保 img 可转换成 RGB8 格式
-    if let Some(rgb_img) = img.as_mut_rgb8() {
-        // 限制 opacity 在有效范围内 [0.0, 1.0]
-        let opacity = opacity.clamp(0.0, 1.0);
-
-        // 预先计算混合颜色的加权值和原始像素的加权因子
-        let mix_red_offset = mix_color[0] as f32 * opacity;
-        let mix_green_offset = mix_color[1] as f32 * opacity;
-        let mix_blue_offset = mix_color[2] as f32 * opacity;
-        let factor = 1.0 - opacity; // 原始像素的权重
-
-        for pixel in rgb_img.pixels_mut() {
-            let current_r = pixel[0] as f32;
-            let current_g = pixel[1] as f32;
-            let current_b = pixel[2] as f32;
-            // alpha 通道保持不变
-
-            let new_r = mix_red_offset + current_r * factor;
-            let new_g = mix_green_offset + current_g * factor;
-            let new_b = mix_blue_offset + current_b * factor;
-
-            // 更新像素数据（确保值在 [0,255] 内）
-            pixel[0] = new_r.clamp(0.0, 255.0) as u8;
-            pixel[1] = new_g.clamp(0.0, 255.0) as u8;
-            pixel[2] = new_b.clamp(0.0, 255.0) as u8;
-        }
-    }
+    let opacity = opacity.clamp(0.0, 1.0);
+    let mix = mix_color.0.map(|c| c as f32 / 255.0 * opacity);
+    filters::map_rgb(img, |rgb| {
+        [0, 1, 2].map(|c| mix[c] + rgb[c] * (1.0 - opacity))
+    });
 }
 
 impl abi::Spec {
@@ -86,6 +291,7 @@ impl abi::Spec {
                 height,
                 rtype: abi::resize::ResizeType::SeamCarve as i32,
                 filter: abi::resize::SampleFilter::Nereast as i32,
+                ..Default::default()
             })),
         }
     }
@@ -97,6 +303,7 @@ impl abi::Spec {
                 height,
                 rtype: abi::resize::ResizeType::Normal as i32,
                 filter: filter as i32,
+                ..Default::default()
             })),
         }
     }
@@ -109,6 +316,12 @@ impl abi::Spec {
         }
     }
 
+    pub fn new_tint(color: u32, opacity: f32) -> Self {
+        Self {
+            data: Some(abi::spec::Data::Tint(abi::Tint { color, opacity })),
+        }
+    }
+
     pub fn new_watermark(x: u32, y: u32) -> Self {
         Self {
             data: Some(abi::spec::Data::Watermark(abi::Watermark { x, y })),
@@ -137,4 +350,73 @@ mod tests {
         );
         assert_eq!(image_spec, s.as_str().try_into().unwrap())
     }
+
+    #[test]
+    fn test_signed_path_verifies_only_untampered_requests() {
+        let secret = b"my-secret";
+        let url = "https://example.com/a.jpg?size=large";
+        let image_spec = abi::ImageSpec::new(vec![abi::Spec::new_watermark(10, 10)]);
+        let spec: String = image_spec.borrow().into();
+
+        let path = image_spec.signed_path(url, secret);
+        let mut segments = path.trim_start_matches("/image/").splitn(3, '/');
+        let signature = segments.next().unwrap();
+        assert_eq!(segments.next().unwrap(), spec);
+        assert_eq!(
+            percent_encoding::percent_decode_str(segments.next().unwrap()).decode_utf8_lossy(),
+            url
+        );
+
+        assert!(verify(secret, signature, &spec, url));
+        assert!(!verify(b"other-secret", signature, &spec, url));
+        assert!(!verify(
+            secret,
+            signature,
+            &spec,
+            "https://example.com/b.jpg"
+        ));
+        assert!(!verify(secret, "not-a-signature", &spec, url));
+    }
+
+    #[test]
+    fn test_output_format_defaults_to_auto() {
+        let image_spec = abi::ImageSpec::new(vec![]);
+        assert_eq!(image_spec.output_format(), abi::format::Format::Auto);
+
+        let image_spec = image_spec.with_format(abi::format::Format::Qoi);
+        let s: String = image_spec.borrow().into();
+        let decoded: abi::ImageSpec = s.as_str().try_into().unwrap();
+        assert_eq!(decoded.output_format(), abi::format::Format::Qoi);
+    }
+
+    #[test]
+    fn test_auto_format_negotiates_accept_header() {
+        let resolve = |format: abi::format::Format, accept, has_alpha| {
+            pick_format(&format.candidates(accept), has_alpha)
+        };
+        let auto = abi::format::Format::Auto;
+        let chrome = "image/avif,image/webp,image/apng,image/*,*/*;q=0.8";
+        assert_eq!(resolve(auto, Some(chrome), false), ImageFormat::WebP);
+        assert_eq!(resolve(auto, Some("*/*"), false), ImageFormat::Jpeg);
+        assert_eq!(resolve(auto, Some("*/*"), true), ImageFormat::Png);
+        assert_eq!(resolve(auto, None, false), ImageFormat::Jpeg);
+        assert_eq!(
+            resolve(auto, Some("image/webp;q=0, image/png"), false),
+            ImageFormat::Png
+        );
+        // a more specific q=0 excludes what a wildcard allows
+        assert_eq!(
+            resolve(auto, Some("image/jpeg;q=0, */*"), false),
+            ImageFormat::Png
+        );
+        assert_eq!(
+            auto.candidates(Some("image/png;q=0, */*")),
+            vec![ImageFormat::Jpeg]
+        );
+        assert!(auto.candidates(Some("image/*;q=0, */*")).is_empty());
+        assert_eq!(
+            resolve(abi::format::Format::Gif, Some("image/webp"), false),
+            ImageFormat::Gif
+        );
+    }
 }
@@ -0,0 +1,152 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicUsize, Ordering},
+};
+
+use serde::Serialize;
+use tokio::sync::Semaphore;
+
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    /// Jobs processed at once.
+    pub workers: usize,
+    /// Jobs allowed to wait for a worker before new ones are rejected.
+    pub max_queue: usize,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            workers: std::thread::available_parallelism().map_or(4, |n| n.get()),
+            max_queue: 64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PoolStats {
+    pub workers: usize,
+    pub busy: usize,
+    pub queued: usize,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PoolError {
+    #[error("all {0} workers are busy and the queue is full")]
+    Saturated(usize),
+    #[error("worker panicked: {0}")]
+    Panicked(#[from] tokio::task::JoinError),
+}
+
+/// Runs CPU heavy jobs on the blocking thread pool, at most `workers` at a time, so they
+/// never stall the async runtime.
+#[derive(Clone)]
+pub struct WorkerPool {
+    permits: Arc<Semaphore>,
+    queued: Arc<AtomicUsize>,
+    config: PoolConfig,
+}
+
+impl WorkerPool {
+    pub fn new(config: PoolConfig) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(config.workers.max(1))),
+            queued: Arc::default(),
+            config,
+        }
+    }
+
+    pub async fn run<F, T>(&self, job: F) -> Result<T, PoolError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let permit = match self.permits.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                let _queued = Queued::enter(&self.queued, self.config.max_queue)
+                    .ok_or(PoolError::Saturated(self.workers()))?;
+                self.permits
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .expect("semaphore is never closed")
+            }
+        };
+
+        Ok(tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            job()
+        })
+        .await?)
+    }
+
+    /// Workers actually running, a configured zero still gets one.
+    fn workers(&self) -> usize {
+        self.config.workers.max(1)
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        let workers = self.workers();
+        PoolStats {
+            workers,
+            busy: workers - self.permits.available_permits(),
+            queued: self.queued.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A slot in the queue, released when the waiting request gets a worker or goes away.
+struct Queued<'a>(&'a AtomicUsize);
+
+impl<'a> Queued<'a> {
+    fn enter(queued: &'a AtomicUsize, max: usize) -> Option<Self> {
+        queued
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < max).then_some(n + 1)
+            })
+            .ok()
+            .map(|_| Self(queued))
+    }
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::mpsc, time::Duration};
+
+    #[tokio::test]
+    async fn test_rejects_jobs_beyond_the_queue() {
+        let pool = WorkerPool::new(PoolConfig {
+            workers: 1,
+            max_queue: 1,
+        });
+        let (release, wait) = mpsc::channel::<()>();
+
+        let busy = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.run(move || wait.recv().unwrap()).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let queued = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.run(|| 2).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let stats = pool.stats();
+        assert_eq!((stats.busy, stats.queued), (1, 1));
+
+        assert!(matches!(pool.run(|| 3).await, Err(PoolError::Saturated(1))));
+
+        release.send(()).unwrap();
+        busy.await.unwrap().unwrap();
+        assert_eq!(queued.await.unwrap().unwrap(), 2);
+        assert_eq!(pool.stats().queued, 0);
+    }
+}
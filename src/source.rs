@@ -0,0 +1,714 @@
+use std::{
+    error::Error as StdError,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use bytes::{Bytes, BytesMut};
+use reqwest::{
+    Client, StatusCode, Url,
+    dns::{Addrs, Name, Resolve, Resolving},
+    header, redirect,
+};
+use sha2::{Digest, Sha256};
+use tracing::info;
+
+/// Which source urls the server is allowed to fetch.
+#[derive(Debug, Clone)]
+pub struct SourcePolicy {
+    pub allowed_schemes: Vec<String>,
+    /// Glob patterns (`*` and `?`), an empty list allows every host.
+    pub allowed_hosts: Vec<String>,
+    /// Glob patterns, checked before `allowed_hosts`.
+    pub denied_hosts: Vec<String>,
+    /// Allow loopback, link-local and private network addresses.
+    pub allow_private: bool,
+}
+
+/// Resource limits for downloading a source image.
+#[derive(Debug, Clone)]
+pub struct DownloadLimits {
+    pub max_bytes: u64,
+    pub connect_timeout: Duration,
+    /// Budget for the whole request, including redirects and the body.
+    pub timeout: Duration,
+    pub max_redirects: usize,
+}
+
+/// A downloaded source image and its upstream validators, protobuf encoded in the source cache.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Source {
+    #[prost(bytes = "bytes", tag = "1")]
+    pub data: Bytes,
+    /// SHA-256 of `data`.
+    #[prost(bytes = "bytes", tag = "2")]
+    pub digest: Bytes,
+    #[prost(string, optional, tag = "3")]
+    pub etag: Option<String>,
+    #[prost(string, optional, tag = "4")]
+    pub last_modified: Option<String>,
+    /// Unix time of the last download or revalidation.
+    #[prost(uint64, tag = "5")]
+    pub fetched_at: u64,
+    /// Upstream `Cache-Control` max-age in seconds.
+    #[prost(uint64, optional, tag = "6")]
+    pub max_age: Option<u64>,
+    /// Upstream sent `no-cache`, every use must be revalidated first.
+    #[prost(bool, tag = "7")]
+    pub no_cache: bool,
+    /// Upstream sent `no-store`, the source must not be cached at all.
+    #[prost(bool, tag = "8")]
+    pub no_store: bool,
+}
+
+/// How a cached `Source` may be used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Freshness {
+    Fresh,
+    /// Servable while it is refreshed in the background.
+    Stale,
+    /// Must be revalidated before use.
+    Expired,
+}
+
+impl Source {
+    /// `ttl` applies when upstream sent no max-age, after which the source stays servable
+    /// for `stale_while_revalidate`. `no-cache` sources are always expired.
+    pub fn freshness(&self, ttl: Duration, stale_while_revalidate: Duration) -> Freshness {
+        if self.no_cache {
+            return Freshness::Expired;
+        }
+        freshness(self.fetched_at, self.max_age, ttl, stale_while_revalidate)
+    }
+}
+
+/// Freshness of anything fetched at `fetched_at` with an upstream `max_age`, see
+/// `Source::freshness`.
+pub fn freshness(
+    fetched_at: u64,
+    max_age: Option<u64>,
+    ttl: Duration,
+    stale_while_revalidate: Duration,
+) -> Freshness {
+    let age = unix_now().saturating_sub(fetched_at);
+    let max_age = max_age.unwrap_or(ttl.as_secs());
+    if age < max_age {
+        Freshness::Fresh
+    } else if age < max_age.saturating_add(stale_while_revalidate.as_secs()) {
+        Freshness::Stale
+    } else {
+        Freshness::Expired
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SourceError {
+    #[error("{0}")]
+    Blocked(String),
+    #[error("source exceeds {0} bytes")]
+    TooLarge(u64),
+    #[error("upstream responded with {0}")]
+    Status(StatusCode),
+    #[error("too many redirects")]
+    TooManyRedirects,
+    #[error("upstream timed out")]
+    Timeout,
+    #[error("unsupported content type {0}")]
+    UnsupportedContentType(String),
+    #[error(transparent)]
+    Request(reqwest::Error),
+}
+
+/// Raised from inside the resolver and redirect policy, recovered from the reqwest error chain.
+#[derive(Debug, thiserror::Error)]
+enum Interrupt {
+    #[error("{0}")]
+    Blocked(String),
+    #[error("too many redirects")]
+    TooManyRedirects,
+}
+
+impl Default for SourcePolicy {
+    fn default() -> Self {
+        Self {
+            allowed_schemes: vec!["http".into(), "https".into()],
+            allowed_hosts: vec![],
+            denied_hosts: vec![],
+            allow_private: false,
+        }
+    }
+}
+
+impl Default for DownloadLimits {
+    fn default() -> Self {
+        Self {
+            max_bytes: 20 * 1024 * 1024,
+            connect_timeout: Duration::from_secs(5),
+            timeout: Duration::from_secs(30),
+            max_redirects: 5,
+        }
+    }
+}
+
+impl SourcePolicy {
+    /// Check scheme, host patterns and literal ip hosts of `url`.
+    pub fn check_url(&self, url: &Url) -> Result<(), SourceError> {
+        if !self
+            .allowed_schemes
+            .iter()
+            .any(|s| s.eq_ignore_ascii_case(url.scheme()))
+        {
+            return Err(SourceError::Blocked(format!(
+                "scheme {} is not allowed",
+                url.scheme()
+            )));
+        }
+
+        let Some(host) = url.host_str() else {
+            return Err(SourceError::Blocked("url has no host".into()));
+        };
+        let host = host.to_ascii_lowercase();
+        if let Ok(ip) = host.trim_matches(['[', ']']).parse::<IpAddr>() {
+            self.check_ip(ip)?;
+        }
+        self.check_host(&host)
+    }
+
+    pub fn check_host(&self, host: &str) -> Result<(), SourceError> {
+        if self.denied_hosts.iter().any(|p| glob_match(p, host)) {
+            return Err(SourceError::Blocked(format!("host {} is denied", host)));
+        }
+        if !self.allowed_hosts.is_empty() && !self.allowed_hosts.iter().any(|p| glob_match(p, host))
+        {
+            return Err(SourceError::Blocked(format!(
+                "host {} is not allowed",
+                host
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn check_ip(&self, ip: IpAddr) -> Result<(), SourceError> {
+        if self.allow_private || is_public(ip) {
+            Ok(())
+        } else {
+            Err(SourceError::Blocked(format!(
+                "address {} is not publicly routable",
+                ip
+            )))
+        }
+    }
+}
+
+/// Fetches source images, enforcing a `SourcePolicy` on every hop and resolved address.
+#[derive(Clone)]
+pub struct Fetcher {
+    client: Client,
+    policy: Arc<SourcePolicy>,
+    max_bytes: u64,
+}
+
+impl Fetcher {
+    pub fn new(policy: SourcePolicy, limits: DownloadLimits) -> reqwest::Result<Self> {
+        let policy = Arc::new(policy);
+
+        let redirect_policy = policy.clone();
+        let max_redirects = limits.max_redirects;
+        let client = Client::builder()
+            // a proxy would resolve the host for us and bypass the address checks
+            .no_proxy()
+            .dns_resolver(Arc::new(PolicyResolver(policy.clone())))
+            .connect_timeout(limits.connect_timeout)
+            .timeout(limits.timeout)
+            .redirect(redirect::Policy::custom(move |attempt| {
+                if attempt.previous().len() > max_redirects {
+                    return attempt.error(Interrupt::TooManyRedirects);
+                }
+                match redirect_policy.check_url(attempt.url()) {
+                    Ok(()) => attempt.follow(),
+                    Err(e) => attempt.error(Interrupt::Blocked(e.to_string())),
+                }
+            }))
+            .build()?;
+
+        Ok(Self {
+            client,
+            policy,
+            max_bytes: limits.max_bytes,
+        })
+    }
+
+    pub async fn fetch(&self, url: &str) -> Result<Source, SourceError> {
+        self.request(url, None).await
+    }
+
+    /// Conditionally re-download `cached`, keeping its content when upstream answers 304.
+    pub async fn revalidate(&self, url: &str, cached: &Source) -> Result<Source, SourceError> {
+        self.request(url, Some(cached)).await
+    }
+
+    async fn request(&self, url: &str, cached: Option<&Source>) -> Result<Source, SourceError> {
+        let url =
+            Url::parse(url).map_err(|e| SourceError::Blocked(format!("invalid url: {}", e)))?;
+        self.policy.check_url(&url)?;
+
+        info!("Retrieve url");
+        let mut req = self.client.get(url);
+        if let Some(cached) = cached {
+            if let Some(etag) = &cached.etag {
+                req = req.header(header::IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &cached.last_modified {
+                req = req.header(header::IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+        let mut resp = req.send().await.map_err(classify)?;
+
+        let validator = |name| {
+            resp.headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(String::from)
+        };
+        let etag = validator(header::ETAG);
+        let last_modified = validator(header::LAST_MODIFIED);
+        let has_cache_control = resp.headers().contains_key(header::CACHE_CONTROL);
+        let CacheDirectives {
+            max_age,
+            no_cache,
+            no_store,
+        } = CacheDirectives::parse(resp.headers());
+
+        if let Some(cached) = cached.filter(|_| resp.status() == StatusCode::NOT_MODIFIED) {
+            info!("Source not modified");
+            let refreshed = Source {
+                etag: etag.or_else(|| cached.etag.clone()),
+                last_modified: last_modified.or_else(|| cached.last_modified.clone()),
+                fetched_at: unix_now(),
+                ..cached.clone()
+            };
+            // Stored directives stay unless the 304 replaces them
+            if !has_cache_control {
+                return Ok(refreshed);
+            }
+            return Ok(Source {
+                max_age,
+                no_cache,
+                no_store,
+                ..refreshed
+            });
+        }
+        if !resp.status().is_success() {
+            return Err(SourceError::Status(resp.status()));
+        }
+        if let Some(content_type) = resp.headers().get(header::CONTENT_TYPE) {
+            let content_type = content_type.to_str().unwrap_or_default();
+            if !is_image_content_type(content_type) {
+                return Err(SourceError::UnsupportedContentType(content_type.into()));
+            }
+        }
+        if resp
+            .content_length()
+            .is_some_and(|len| len > self.max_bytes)
+        {
+            return Err(SourceError::TooLarge(self.max_bytes));
+        }
+
+        // Content-Length may be absent or wrong, so enforce the limit while streaming
+        let mut data = BytesMut::new();
+        while let Some(chunk) = resp.chunk().await.map_err(classify)? {
+            if (data.len() + chunk.len()) as u64 > self.max_bytes {
+                return Err(SourceError::TooLarge(self.max_bytes));
+            }
+            data.extend_from_slice(&chunk);
+        }
+
+        // Sniff the payload, upstream content types are often generic or wrong
+        if image::guess_format(&data).is_err() {
+            return Err(SourceError::UnsupportedContentType(
+                "unrecognized image data".into(),
+            ));
+        }
+        let digest = Sha256::digest(&data);
+        Ok(Source {
+            data: data.freeze(),
+            digest: Bytes::copy_from_slice(&digest),
+            etag,
+            last_modified,
+            fetched_at: unix_now(),
+            max_age,
+            no_cache,
+            no_store,
+        })
+    }
+}
+
+/// The upstream `Cache-Control` directives the source cache honours.
+#[derive(Debug, Default, PartialEq)]
+struct CacheDirectives {
+    max_age: Option<u64>,
+    no_cache: bool,
+    no_store: bool,
+}
+
+impl CacheDirectives {
+    fn parse(headers: &header::HeaderMap) -> Self {
+        let mut directives = Self::default();
+        for directive in headers
+            .get_all(header::CACHE_CONTROL)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+        {
+            let directive = directive.trim().to_ascii_lowercase();
+            match directive.as_str() {
+                "no-cache" => directives.no_cache = true,
+                "no-store" => directives.no_store = true,
+                _ => {
+                    if let Some(secs) = directive.strip_prefix("max-age=") {
+                        directives.max_age = secs.trim_matches('"').parse().ok();
+                    }
+                }
+            }
+        }
+        directives
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+fn is_image_content_type(content_type: &str) -> bool {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    mime.starts_with("image/") || mime == "application/octet-stream" || mime.is_empty()
+}
+
+/// Map a reqwest error to `SourceError`, surfacing policy violations hidden in its chain.
+fn classify(err: reqwest::Error) -> SourceError {
+    let mut source = err.source();
+    while let Some(e) = source {
+        match e.downcast_ref::<Interrupt>() {
+            Some(Interrupt::Blocked(reason)) => return SourceError::Blocked(reason.clone()),
+            Some(Interrupt::TooManyRedirects) => return SourceError::TooManyRedirects,
+            None => source = e.source(),
+        }
+    }
+    if err.is_timeout() {
+        SourceError::Timeout
+    } else {
+        SourceError::Request(err)
+    }
+}
+
+/// Resolves with the system resolver and rejects hosts with any non-public address,
+/// which also covers redirects and DNS rebinding.
+struct PolicyResolver(Arc<SourcePolicy>);
+
+impl Resolve for PolicyResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let policy = self.0.clone();
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> =
+                tokio::net::lookup_host((name.as_str(), 0)).await?.collect();
+            for addr in addrs.iter() {
+                policy.check_ip(addr.ip()).map_err(|e| {
+                    Interrupt::Blocked(format!("{} resolves to {}", name.as_str(), e))
+                })?;
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                // shared address space 100.64.0.0/10
+                || (a == 100 && (b & 0xc0) == 64)
+                // "this network" 0.0.0.0/8
+                || a == 0)
+        }
+        IpAddr::V6(ip) => match embedded_ipv4(ip) {
+            Some(v4) => is_public(IpAddr::V4(v4)),
+            None => {
+                let [a, ..] = ip.segments();
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_unique_local()
+                    || ip.is_unicast_link_local()
+                    || ip.is_multicast()
+                    // deprecated site-local fec0::/10
+                    || (a & 0xffc0) == 0xfec0)
+            }
+        },
+    }
+}
+
+/// IPv4 address an IPv6 address routes to: v4-mapped `::ffff:a.b.c.d`, v4-compatible
+/// `::a.b.c.d`, NAT64 `64:ff9b::a.b.c.d` and 6to4 `2002:aabb:ccdd::`.
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let octets = ip.octets();
+    let [a, b, ..] = ip.segments();
+    let tail = Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15]);
+    match a {
+        // mapped and compatible, except the loopback and unspecified addresses
+        0 if !ip.is_loopback() && !ip.is_unspecified() => ip.to_ipv4(),
+        0x64 if b == 0xff9b && ip.segments()[2..6] == [0; 4] => Some(tail),
+        0x2002 => Some(Ipv4Addr::new(octets[2], octets[3], octets[4], octets[5])),
+        _ => None,
+    }
+}
+
+/// Case-insensitive glob match supporting `*` (any run) and `?` (one char).
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_ascii_lowercase().chars().collect();
+    let text: Vec<char> = text.to_ascii_lowercase().chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((bp, bt)) => {
+                    p = bp + 1;
+                    t = bt + 1;
+                    backtrack = Some((bp, bt + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{png, serve};
+
+    fn local_fetcher(limits: DownloadLimits) -> Fetcher {
+        let policy = SourcePolicy {
+            allow_private: true,
+            ..Default::default()
+        };
+        Fetcher::new(policy, limits).unwrap()
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*.example.com", "cdn.EXAMPLE.com"));
+        assert!(!glob_match("*.example.com", "example.com"));
+        assert!(glob_match("img?.example.com", "img1.example.com"));
+        assert!(glob_match("*", "anything"));
+        assert!(!glob_match("example.com", "example.com.evil.org"));
+    }
+
+    #[test]
+    fn test_private_addresses_are_not_public() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "::",
+            "::127.0.0.1",
+            "::10.0.0.1",
+            "64:ff9b::a9fe:a9fe",
+            "64:ff9b::127.0.0.1",
+            "2002:7f00:1::",
+            "2002:a00:1::1",
+            "fec0::1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+        }
+        assert!(is_public("93.184.216.34".parse().unwrap()));
+        assert!(is_public("2606:4700::1111".parse().unwrap()));
+        assert!(is_public("64:ff9b::5db8:d822".parse().unwrap()));
+        assert!(is_public("2002:5db8:d822::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_policy_checks_scheme_and_hosts() {
+        let policy = SourcePolicy {
+            allowed_hosts: vec!["*.example.com".into()],
+            denied_hosts: vec!["private.example.com".into()],
+            ..Default::default()
+        };
+        let check = |url: &str| policy.check_url(&Url::parse(url).unwrap()).is_ok();
+
+        assert!(check("https://cdn.example.com/a.png"));
+        assert!(!check("file:///etc/passwd"));
+        assert!(!check("ftp://cdn.example.com/a.png"));
+        assert!(!check("https://private.example.com/a.png"));
+        assert!(!check("https://example.org/a.png"));
+        assert!(!check("http://127.0.0.1/a.png"));
+    }
+
+    #[test]
+    fn test_max_age_and_freshness() {
+        let headers = |value: &'static str| {
+            let mut headers = header::HeaderMap::new();
+            headers.insert(
+                header::CACHE_CONTROL,
+                header::HeaderValue::from_static(value),
+            );
+            headers
+        };
+        let parse = |value| CacheDirectives::parse(&headers(value));
+        assert_eq!(parse("public, max-age=60").max_age, Some(60));
+        assert!(parse("max-age=60, no-cache").no_cache);
+        assert!(parse("No-Store").no_store);
+        assert_eq!(
+            CacheDirectives::parse(&header::HeaderMap::new()),
+            CacheDirectives::default()
+        );
+
+        let (ttl, swr) = (Duration::from_secs(10), Duration::from_secs(10));
+        let source = |age: u64, max_age: Option<u64>| Source {
+            fetched_at: unix_now() - age,
+            max_age,
+            ..Default::default()
+        };
+        assert_eq!(source(5, None).freshness(ttl, swr), Freshness::Fresh);
+        assert_eq!(source(15, None).freshness(ttl, swr), Freshness::Stale);
+        assert_eq!(source(25, None).freshness(ttl, swr), Freshness::Expired);
+        assert_eq!(source(5, Some(0)).freshness(ttl, swr), Freshness::Stale);
+        assert_eq!(source(15, Some(60)).freshness(ttl, swr), Freshness::Fresh);
+        let no_cache = Source {
+            no_cache: true,
+            ..source(0, Some(60))
+        };
+        assert_eq!(no_cache.freshness(ttl, swr), Freshness::Expired);
+    }
+
+    #[tokio::test]
+    async fn test_revalidate_keeps_unmodified_content() {
+        let addr = serve().await;
+        let fetcher = local_fetcher(DownloadLimits::default());
+        let url = format!("http://{}/image", addr);
+
+        let mut source = fetcher.fetch(&url).await.unwrap();
+        assert_eq!(source.max_age, Some(60));
+        source.fetched_at = 0;
+
+        // The 304 carries no Cache-Control, so the cached lifetime is kept
+        let revalidated = fetcher.revalidate(&url, &source).await.unwrap();
+        assert_eq!(revalidated.digest, source.digest);
+        assert_eq!(revalidated.etag.as_deref(), Some("\"v1\""));
+        assert_eq!(revalidated.max_age, Some(60));
+        assert!(revalidated.fetched_at > 0);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_blocks_loopback_by_default() {
+        let addr = serve().await;
+        let fetcher = Fetcher::new(SourcePolicy::default(), DownloadLimits::default()).unwrap();
+
+        let err = fetcher
+            .fetch(&format!("http://{}/image", addr))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, SourceError::Blocked(_)));
+
+        let err = fetcher
+            .fetch(&format!("http://localhost:{}/image", addr.port()))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, SourceError::Blocked(_)));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_checks_redirect_targets() {
+        let addr = serve().await;
+        let fetcher = local_fetcher(DownloadLimits::default());
+        let source = fetcher
+            .fetch(&format!("http://{}/redirect", addr))
+            .await
+            .unwrap();
+        assert_eq!(source.data.as_ref(), png());
+        assert_eq!(source.etag.as_deref(), Some("\"v1\""));
+        assert_eq!(source.digest.as_ref(), Sha256::digest(png()).as_slice());
+
+        let policy = SourcePolicy {
+            allow_private: true,
+            denied_hosts: vec!["localhost".into()],
+            ..Default::default()
+        };
+        let fetcher = Fetcher::new(policy, DownloadLimits::default()).unwrap();
+        let err = fetcher
+            .fetch(&format!("http://{}/redirect", addr))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, SourceError::Blocked(_)));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_enforces_download_limits() {
+        let addr = serve().await;
+        let fetcher = local_fetcher(DownloadLimits {
+            max_bytes: 1024,
+            timeout: Duration::from_millis(500),
+            max_redirects: 2,
+            ..Default::default()
+        });
+        let url = |path: &str| format!("http://{}{}", addr, path);
+
+        assert!(fetcher.fetch(&url("/image")).await.is_ok());
+        assert!(matches!(
+            fetcher.fetch(&url("/big")).await,
+            Err(SourceError::TooLarge(1024))
+        ));
+        assert!(matches!(
+            fetcher.fetch(&url("/missing")).await,
+            Err(SourceError::Status(StatusCode::NOT_FOUND))
+        ));
+        assert!(matches!(
+            fetcher.fetch(&url("/html")).await,
+            Err(SourceError::UnsupportedContentType(_))
+        ));
+        assert!(matches!(
+            fetcher.fetch(&url("/text")).await,
+            Err(SourceError::UnsupportedContentType(_))
+        ));
+        assert!(matches!(
+            fetcher.fetch(&url("/loop")).await,
+            Err(SourceError::TooManyRedirects)
+        ));
+        assert!(matches!(
+            fetcher.fetch(&url("/slow")).await,
+            Err(SourceError::Timeout)
+        ));
+    }
+}
@@ -0,0 +1,67 @@
+use std::{net::SocketAddr, time::Duration};
+
+use axum::{
+    Router,
+    http::{HeaderMap, StatusCode, header},
+    response::{Html, IntoResponse, Redirect},
+    routing::get,
+};
+use tokio::net::TcpListener;
+
+pub fn png() -> Vec<u8> {
+    let mut buf = Vec::new();
+    image::DynamicImage::new_rgb8(1, 1)
+        .write_to(&mut std::io::Cursor::new(&mut buf), image::ImageFormat::Png)
+        .unwrap();
+    buf
+}
+
+/// Serve source images and failure cases on a local port.
+pub async fn serve() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let app = Router::new()
+        .route(
+            "/image",
+            get(|headers: HeaderMap| async move {
+                if headers
+                    .get(header::IF_NONE_MATCH)
+                    .is_some_and(|v| v == "\"v1\"")
+                {
+                    return StatusCode::NOT_MODIFIED.into_response();
+                }
+                (
+                    [
+                        (header::CONTENT_TYPE, "image/png"),
+                        (header::ETAG, "\"v1\""),
+                        (header::CACHE_CONTROL, "public, max-age=60"),
+                    ],
+                    png(),
+                )
+                    .into_response()
+            }),
+        )
+        .route(
+            "/redirect",
+            get(move || async move {
+                Redirect::temporary(&format!("http://localhost:{}/image", addr.port()))
+            }),
+        )
+        .route("/loop", get(|| async { Redirect::temporary("/loop") }))
+        .route(
+            "/missing",
+            get(|| async { (StatusCode::NOT_FOUND, "not found") }),
+        )
+        .route("/html", get(|| async { Html("<html></html>") }))
+        .route("/text", get(|| async { "not an image" }))
+        .route("/big", get(|| async { vec![0_u8; 4096] }))
+        .route(
+            "/slow",
+            get(|| async {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                png()
+            }),
+        );
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    addr
+}